@@ -0,0 +1,109 @@
+//! Plays LED effects on a thread of their own, so the main loop never waits for LED timing.
+//!
+//! ```ignore
+//! let effects = Effects::start(WS2812RMT::new()?)?;
+//! effects.set(Effect::Blink {
+//!     color: RGB8::new(0, 0, 50),
+//!     on: Duration::from_millis(500),
+//!     off: Duration::from_millis(500),
+//! });
+//! ```
+//!
+//! The thread owns the `WS2812RMT`. It turns the LEDs off and ends once
+//! every `Effects` handle has been dropped.
+
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use led_effects::render;
+pub use led_effects::{blink_code_period, Effect};
+pub(crate) use led_effects::{CODE_BLINK, CODE_PAUSE};
+use log::error;
+
+use crate::led::{RGB8, WS2812RMT};
+
+const FRAME: Duration = Duration::from_millis(20);
+
+enum Request {
+    Set(Effect),
+    Flash { color: RGB8, duration: Duration },
+}
+
+/// Handle to the effects thread. Cloning it gives another handle to the same thread.
+#[derive(Clone)]
+pub struct Effects {
+    sender: Sender<Request>,
+}
+
+impl Effects {
+    /// Starts the effects thread with all LEDs off.
+    pub fn start(led: WS2812RMT) -> anyhow::Result<Self> {
+        let (sender, receiver) = mpsc::channel();
+        thread::Builder::new()
+            .name("led-effects".into())
+            .stack_size(4096)
+            .spawn(move || run(led, receiver))?;
+        Ok(Self { sender })
+    }
+
+    /// Replaces the current effect. Never blocks.
+    pub fn set(&self, effect: Effect) {
+        self.send(Request::Set(effect));
+    }
+
+    /// Shows `color` for `duration`, then restarts the current effect. Never blocks.
+    pub fn flash(&self, color: RGB8, duration: Duration) {
+        self.send(Request::Flash { color, duration });
+    }
+
+    fn send(&self, request: Request) {
+        // only fails if the thread is gone, and then there's nothing left to show
+        let _ = self.sender.send(request);
+    }
+}
+
+fn run(mut led: WS2812RMT, receiver: Receiver<Request>) {
+    let pixel_count = led.pixel_count();
+    let mut effect = Effect::Off;
+    let mut start = Instant::now();
+    let mut flash: Option<(RGB8, Instant)> = None;
+    let mut from = vec![RGB8::default(); pixel_count];
+    let mut frame = vec![RGB8::default(); pixel_count];
+    let mut shown = None;
+
+    loop {
+        match receiver.recv_timeout(FRAME) {
+            Ok(Request::Set(next)) => {
+                effect = next;
+                start = Instant::now();
+                from.copy_from_slice(&frame);
+            }
+            Ok(Request::Flash { color, duration }) => {
+                flash = Some((color, Instant::now() + duration));
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        match flash {
+            Some((color, until)) if Instant::now() < until => frame.fill(color),
+            Some(_) => {
+                flash = None;
+                start = Instant::now();
+                render(&effect, start.elapsed(), &from, &mut frame);
+            }
+            None => render(&effect, start.elapsed(), &from, &mut frame),
+        }
+        if shown.as_ref() != Some(&frame) {
+            if let Err(e) = led.set_pixels(&frame) {
+                error!("could not show LED effect: {:?}", e);
+            }
+            shown = Some(frame.clone());
+        }
+    }
+
+    if let Err(e) = led.set_pixel(RGB8::default()) {
+        error!("could not turn LEDs off: {:?}", e);
+    }
+}
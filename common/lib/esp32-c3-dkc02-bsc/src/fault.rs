@@ -0,0 +1,220 @@
+//! Makes fatal errors visible without a serial cable.
+//!
+//! A panic, or an error returned from `fault::run`, blinks an error code on
+//! the board LED, then restarts the board. The reason survives the restart
+//! in RTC memory and is logged on the next boot:
+//!
+//! ```ignore
+//! fn main() -> anyhow::Result<()> {
+//!     esp_idf_sys::link_patches();
+//!     fault::install_panic_hook();
+//!     if let Some(fault) = fault::take_last_fault() {
+//!         warn!("previous boot failed with {}", fault);
+//!     }
+//!     fault::run(|| {
+//!         let _wifi = wifi(ssid, psk)?; // blinks `FaultCode::Wifi` on error
+//!         let client = EspMqttClient::new(url, &config).context(FaultCode::Mqtt)?;
+//!         // ...
+//!     })
+//! }
+//! ```
+//!
+//! The LED blinks red as many times as the code says, pauses, and repeats.
+//! With a status indicator installed, it blinks through that. Otherwise the
+//! LED is taken over directly, which only works if nothing else still holds
+//! it, e.g. not for a panic in an app that drives the LED itself. The reason
+//! is recorded either way.
+
+use std::fmt;
+use std::thread;
+
+use log::error;
+
+use crate::effects::{blink_code_period, CODE_BLINK, CODE_PAUSE};
+use crate::led::{RGB8, WS2812RMT};
+use crate::status::{self, Status};
+
+/// What went wrong, as the number of blinks. Attach it to an error with
+/// `anyhow::Context::context` so `run` picks it up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultCode {
+    /// An error without a more specific code
+    Other = 1,
+    Wifi = 2,
+    Mqtt = 3,
+    Sensor = 4,
+    Panic = 5,
+}
+
+impl FaultCode {
+    pub const COLOR: RGB8 = RGB8 { r: 50, g: 0, b: 0 };
+
+    pub fn blinks(self) -> u8 {
+        self as u8
+    }
+
+    fn from_u8(code: u8) -> Option<Self> {
+        match code {
+            1 => Some(FaultCode::Other),
+            2 => Some(FaultCode::Wifi),
+            3 => Some(FaultCode::Mqtt),
+            4 => Some(FaultCode::Sensor),
+            5 => Some(FaultCode::Panic),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for FaultCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            FaultCode::Other => "error",
+            FaultCode::Wifi => "Wi-Fi error",
+            FaultCode::Mqtt => "MQTT error",
+            FaultCode::Sensor => "sensor error",
+            FaultCode::Panic => "panic",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Why the previous boot ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fault {
+    pub code: FaultCode,
+    pub reason: String,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} (code {}): {}",
+            self.code,
+            self.code.blinks(),
+            self.reason
+        )
+    }
+}
+
+const MAGIC: u32 = 0xfa17_c0de;
+const REASON_SIZE: usize = 200;
+
+/// Kept in RTC memory, which is not cleared by a software restart.
+#[repr(C)]
+struct FaultRecord {
+    magic: u32,
+    code: u8,
+    len: u8,
+    reason: [u8; REASON_SIZE],
+}
+
+#[link_section = ".rtc_noinit"]
+static mut LAST_FAULT: FaultRecord = FaultRecord {
+    magic: 0,
+    code: 0,
+    len: 0,
+    reason: [0; REASON_SIZE],
+};
+
+/// How often the code is blinked before restarting
+const REPETITIONS: u32 = 3;
+
+/// Returns why the previous boot failed, if it did, and forgets about it.
+pub fn take_last_fault() -> Option<Fault> {
+    // only touched before restarting and here, both from a single thread
+    let record = unsafe { &mut LAST_FAULT };
+    if record.magic != MAGIC {
+        return None;
+    }
+    record.magic = 0;
+
+    let len = (record.len as usize).min(REASON_SIZE);
+    Some(Fault {
+        code: FaultCode::from_u8(record.code)?,
+        reason: String::from_utf8_lossy(&record.reason[..len]).into_owned(),
+    })
+}
+
+/// Records, blinks and restarts on panics.
+pub fn install_panic_hook() {
+    std::panic::set_hook(Box::new(|info| {
+        let reason = info.to_string();
+        error!("{}", reason);
+        fail(FaultCode::Panic, &reason);
+    }));
+}
+
+/// Runs the app; if it returns an error, records and blinks it, then restarts.
+///
+/// The code is taken from a `FaultCode` context of the error, if there is one.
+pub fn run(app: impl FnOnce() -> anyhow::Result<()>) -> anyhow::Result<()> {
+    if let Err(e) = app() {
+        let code = e
+            .downcast_ref::<FaultCode>()
+            .copied()
+            .unwrap_or(FaultCode::Other);
+        let reason = reason(&e, code);
+        error!("{}: {}", code, reason);
+        fail(code, &reason);
+    }
+    Ok(())
+}
+
+/// The error chain without the `FaultCode` context, which is shown separately.
+fn reason(e: &anyhow::Error, code: FaultCode) -> String {
+    let code = code.to_string();
+    let causes: Vec<String> = e
+        .chain()
+        .map(|cause| cause.to_string())
+        .filter(|cause| *cause != code)
+        .collect();
+    if causes.is_empty() {
+        // the code is all there is
+        format!("{:#}", e)
+    } else {
+        causes.join(": ")
+    }
+}
+
+/// Records `reason`, blinks `code` a few times and restarts the board.
+pub fn fail(code: FaultCode, reason: &str) -> ! {
+    record(code, reason);
+
+    let blink_for = blink_code_period(code.blinks()) * REPETITIONS;
+    if status::is_installed() {
+        status::report(Status::Fault(code));
+        thread::sleep(blink_for);
+    } else if let Ok(mut led) = WS2812RMT::new() {
+        blink(&mut led, code);
+    }
+
+    unsafe { esp_idf_sys::esp_restart() }
+}
+
+fn record(code: FaultCode, reason: &str) {
+    // cut at a character boundary that fits
+    let mut len = reason.len().min(REASON_SIZE);
+    while !reason.is_char_boundary(len) {
+        len -= 1;
+    }
+
+    let record = unsafe { &mut LAST_FAULT };
+    record.code = code as u8;
+    record.len = len as u8;
+    record.reason[..len].copy_from_slice(&reason.as_bytes()[..len]);
+    record.magic = MAGIC;
+}
+
+/// Blinks without the effects thread, for when there is no status indicator.
+fn blink(led: &mut WS2812RMT, code: FaultCode) {
+    for _ in 0..REPETITIONS {
+        for _ in 0..code.blinks() {
+            let _ = led.set_pixel(FaultCode::COLOR);
+            thread::sleep(CODE_BLINK);
+            let _ = led.set_pixel(RGB8::default());
+            thread::sleep(CODE_BLINK);
+        }
+        thread::sleep(CODE_PAUSE);
+    }
+}
@@ -1,29 +1,172 @@
 use std::ptr::{null, null_mut};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::thread;
+use std::time::Instant;
 
 use esp_idf_sys::{
-    c_types::c_void, esp, rmt_config, rmt_config_t, rmt_config_t__bindgen_ty_1, rmt_driver_install,
-    rmt_get_counter_clock, rmt_item32_t, rmt_item32_t__bindgen_ty_1,
-    rmt_item32_t__bindgen_ty_1__bindgen_ty_1, rmt_mode_t_RMT_MODE_TX, rmt_translator_init,
-    rmt_tx_config_t, rmt_wait_tx_done, rmt_write_sample, size_t, u_int8_t,
+    c_types::c_void, esp, esp_err_t, rmt_config, rmt_config_t, rmt_config_t__bindgen_ty_1,
+    rmt_driver_install, rmt_driver_uninstall, rmt_get_counter_clock, rmt_item32_t,
+    rmt_item32_t__bindgen_ty_1, rmt_item32_t__bindgen_ty_1__bindgen_ty_1, rmt_mode_t_RMT_MODE_TX,
+    rmt_translator_get_context, rmt_translator_init, rmt_translator_set_context, rmt_tx_config_t,
+    rmt_wait_tx_done, rmt_write_sample, size_t, u_int8_t, ESP_OK,
 };
+pub use led_color::{estimated_current_ma, kelvin, ColorCorrection, Hsl, Hsv};
 pub use rgb::RGB8;
+pub use smart_leds_trait::SmartLedsWrite;
+pub use ws2812_encoder::{ColorOrder, LedChip, Timing};
+use ws2812_encoder::{Encoder, Pulse};
 
-const WS2812_T0H_NS: u32 = 350;
-const WS2812_T0L_NS: u32 = 1000;
-const WS2812_T1H_NS: u32 = 1000;
-const WS2812_T1L_NS: u32 = 350;
-
-#[derive(Debug, Default, Clone, Copy)]
-struct Ws2812Config {
-    t0h_ticks: u32,
-    t0l_ticks: u32,
-    t1h_ticks: u32,
-    t1l_ticks: u32,
+const FREERTOS_HZ: u32 = 1000;
+
+/// The ESP32-C3 has four RMT channels of one memory block each; only the first two can transmit.
+const RMT_CHANNELS: u8 = 4;
+const RMT_TX_CHANNELS: u8 = 2;
+
+/// Bit mask of the RMT memory blocks claimed by `WS2812RMT` instances
+static RMT_BLOCKS_IN_USE: AtomicU8 = AtomicU8::new(0);
+
+/// Where and how WS2812 LEDs are connected.
+///
+/// The default is the board LED. A strip on another pin looks like this:
+///
+/// ```ignore
+/// let strip = WS2812RMT::with_config(
+///     LedConfig::new()
+///         .gpio(8)
+///         .channel(1)
+///         .chip(LedChip::Sk6812Rgbw)
+///         .pixel_count(30),
+/// )?;
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct LedConfig {
+    pub gpio: i32,
+    pub channel: u8,
+    /// Divides the 80MHz APB clock down to the RMT tick
+    pub clk_div: u8,
+    /// RMT memory blocks, taken from `channel` and the channels after it
+    pub mem_blocks: u8,
+    pub pixel_count: usize,
+    pub timing: Timing,
+    pub color_order: ColorOrder,
+    pub correction: ColorCorrection,
 }
 
-const FREERTOS_HZ: u32 = 1000;
+impl Default for LedConfig {
+    fn default() -> Self {
+        Self {
+            gpio: 2,
+            channel: 0,
+            clk_div: 2,
+            mem_blocks: 1,
+            pixel_count: 1,
+            timing: Timing::WS2812,
+            color_order: ColorOrder::Grb,
+            correction: ColorCorrection::default(),
+        }
+    }
+}
+
+impl LedConfig {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn gpio(mut self, gpio: i32) -> Self {
+        self.gpio = gpio;
+        self
+    }
+
+    pub fn channel(mut self, channel: u8) -> Self {
+        self.channel = channel;
+        self
+    }
+
+    pub fn clk_div(mut self, clk_div: u8) -> Self {
+        self.clk_div = clk_div;
+        self
+    }
+
+    pub fn mem_blocks(mut self, mem_blocks: u8) -> Self {
+        self.mem_blocks = mem_blocks;
+        self
+    }
+
+    pub fn pixel_count(mut self, pixel_count: usize) -> Self {
+        self.pixel_count = pixel_count;
+        self
+    }
+
+    pub fn timing(mut self, timing: Timing) -> Self {
+        self.timing = timing;
+        self
+    }
+
+    pub fn color_order(mut self, color_order: ColorOrder) -> Self {
+        self.color_order = color_order;
+        self
+    }
+
+    pub fn correction(mut self, correction: ColorCorrection) -> Self {
+        self.correction = correction;
+        self
+    }
 
-static mut WS_CONFIG: Option<Ws2812Config> = None;
+    /// Sets timing and color order of a known LED chip.
+    pub fn chip(self, chip: LedChip) -> Self {
+        self.timing(chip.timing()).color_order(chip.color_order())
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.pixel_count == 0 {
+            anyhow::bail!("a strip needs at least one pixel");
+        }
+        if self.channel >= RMT_TX_CHANNELS {
+            anyhow::bail!(
+                "RMT channel {} can't transmit, use 0 to {}",
+                self.channel,
+                RMT_TX_CHANNELS - 1
+            );
+        }
+        if self.mem_blocks == 0 || self.channel + self.mem_blocks > RMT_CHANNELS {
+            anyhow::bail!(
+                "RMT channel {} can use 1 to {} memory blocks, not {}",
+                self.channel,
+                RMT_CHANNELS - self.channel,
+                self.mem_blocks
+            );
+        }
+        if self.clk_div == 0 {
+            anyhow::bail!("the RMT clock divider can't be 0");
+        }
+        Ok(())
+    }
+
+    /// The memory blocks this configuration claims, as a bit mask
+    fn blocks(&self) -> u8 {
+        ((1u8 << self.mem_blocks) - 1) << self.channel
+    }
+}
+
+/// Claims the memory blocks of `config`, failing if another driver already uses any of them.
+fn claim_rmt_blocks(config: &LedConfig) -> anyhow::Result<()> {
+    let blocks = config.blocks();
+    let claimed = RMT_BLOCKS_IN_USE.fetch_or(blocks, Ordering::SeqCst);
+    if claimed & blocks != 0 {
+        // give back only the blocks we just set
+        RMT_BLOCKS_IN_USE.fetch_and(!(blocks & !claimed), Ordering::SeqCst);
+        anyhow::bail!(
+            "RMT channel {} with {} memory block(s) overlaps a channel already in use",
+            config.channel,
+            config.mem_blocks
+        );
+    }
+    Ok(())
+}
+
+fn release_rmt_blocks(config: &LedConfig) {
+    RMT_BLOCKS_IN_USE.fetch_and(!config.blocks(), Ordering::SeqCst);
+}
 
 unsafe extern "C" fn ws2812_to_rmt(
     src: *const c_void,
@@ -33,36 +176,19 @@ unsafe extern "C" fn ws2812_to_rmt(
     translated_size: *mut size_t,
     item_num: *mut size_t,
 ) {
-    if src == null() || dest == null_mut() {
+    let mut context: *mut c_void = null_mut();
+    if src == null()
+        || dest == null_mut()
+        || rmt_translator_get_context(item_num, &mut context) != ESP_OK as esp_err_t
+        || context.is_null()
+    {
         *translated_size = 0;
         *item_num = 0;
         return;
     }
 
-    let config = WS_CONFIG.unwrap();
-    let mut bit0: rmt_item32_t__bindgen_ty_1__bindgen_ty_1 = Default::default();
-    bit0.set_duration0(config.t0h_ticks);
-    bit0.set_level0(1);
-    bit0.set_duration1(config.t0l_ticks);
-    bit0.set_level1(0);
-
-    let bit0 = rmt_item32_t {
-        __bindgen_anon_1: rmt_item32_t__bindgen_ty_1 {
-            __bindgen_anon_1: bit0,
-        },
-    };
-
-    let mut bit1: rmt_item32_t__bindgen_ty_1__bindgen_ty_1 = Default::default();
-    bit1.set_duration0(config.t1h_ticks);
-    bit1.set_level0(1);
-    bit1.set_duration1(config.t1l_ticks);
-    bit1.set_level1(0);
-
-    let bit1 = rmt_item32_t {
-        __bindgen_anon_1: rmt_item32_t__bindgen_ty_1 {
-            __bindgen_anon_1: bit1,
-        },
-    };
+    // set by `WS2812RMT::install`, lives as long as the driver
+    let encoder = &*(context as *const Encoder);
 
     let mut size: size_t = 0;
     let mut num = 0;
@@ -71,12 +197,8 @@ unsafe extern "C" fn ws2812_to_rmt(
     let mut pdest: *mut rmt_item32_t = dest as _;
 
     while size < src_size && num < wanted_num {
-        for i in 0..8 {
-            if *psrc & (1 << (7 - i)) != 0 {
-                *pdest = bit1;
-            } else {
-                *pdest = bit0;
-            }
+        for bit in encoder.byte(*psrc) {
+            *pdest = rmt_item(bit);
             num += 1;
             pdest = pdest.add(1);
         }
@@ -87,11 +209,63 @@ unsafe extern "C" fn ws2812_to_rmt(
     *item_num = num;
 }
 
+fn rmt_item([high, low]: [Pulse; 2]) -> rmt_item32_t {
+    let mut item: rmt_item32_t__bindgen_ty_1__bindgen_ty_1 = Default::default();
+    item.set_duration0(high.ticks as u32);
+    item.set_level0(high.level as u32);
+    item.set_duration1(low.ticks as u32);
+    item.set_level1(low.level as u32);
+
+    rmt_item32_t {
+        __bindgen_anon_1: rmt_item32_t__bindgen_ty_1 {
+            __bindgen_anon_1: item,
+        },
+    }
+}
+
+/// Driver for WS2812 LEDs: the board LED, or a strip of them.
+///
+/// The pixels are kept in a frame buffer and sent in one RMT transaction.
+/// `ws2812_to_rmt` feeds the RMT memory block by block while the frame is
+/// sent, so the length of a strip is not limited by the RMT memory.
 pub struct WS2812RMT {
     config: rmt_config_t,
+    led_config: LedConfig,
+    pixels: Vec<RGB8>,
+    correction: ColorCorrection,
+    /// `pixels` after `correction`
+    corrected: Vec<RGB8>,
+    /// The frame as sent on the wire, in the LEDs' color order
+    data: Vec<u8>,
+    last_frame_end: Option<Instant>,
+    /// Context of `ws2812_to_rmt`, boxed so it doesn't move with the driver
+    encoder: Box<Encoder>,
 }
 impl WS2812RMT {
+    /// The board LED.
     pub fn new() -> anyhow::Result<Self> {
+        Self::with_config(LedConfig::default())
+    }
+
+    /// A strip of `pixel_count` LEDs, connected to the pin of the board LED.
+    pub fn new_strip(pixel_count: usize) -> anyhow::Result<Self> {
+        Self::with_config(LedConfig::default().pixel_count(pixel_count))
+    }
+
+    /// LEDs on any pin and RMT channel. Fails if `led_config` is invalid or
+    /// its channel is already used by another `WS2812RMT`.
+    pub fn with_config(led_config: LedConfig) -> anyhow::Result<Self> {
+        led_config.validate()?;
+        claim_rmt_blocks(&led_config)?;
+
+        let result = Self::install(led_config);
+        if result.is_err() {
+            release_rmt_blocks(&led_config);
+        }
+        result
+    }
+
+    fn install(led_config: LedConfig) -> anyhow::Result<Self> {
         let rmt_tx_config = rmt_tx_config_t {
             carrier_freq_hz: 38000,
             carrier_level: 1,
@@ -105,52 +279,171 @@ impl WS2812RMT {
 
         let config = rmt_config_t {
             rmt_mode: rmt_mode_t_RMT_MODE_TX,
-            channel: 0,
-            gpio_num: 2,
-            clk_div: 2,
-            mem_block_num: 1,
+            channel: led_config.channel as _,
+            gpio_num: led_config.gpio,
+            clk_div: led_config.clk_div,
+            mem_block_num: led_config.mem_blocks,
             flags: 0,
             __bindgen_anon_1: rmt_config_t__bindgen_ty_1 {
                 tx_config: rmt_tx_config,
             },
         };
 
-        unsafe {
+        let encoder = unsafe {
             esp!(rmt_config(&config))?;
             esp!(rmt_driver_install(config.channel, 0, 0))?;
             let mut rmt_clock = 0u32;
-            esp!(rmt_get_counter_clock(config.channel, &mut rmt_clock))?;
+            if let Err(e) = esp!(rmt_get_counter_clock(config.channel, &mut rmt_clock)) {
+                rmt_driver_uninstall(config.channel);
+                return Err(e.into());
+            }
+
+            let encoder =
+                match Encoder::with_timing(&led_config.timing, led_config.color_order, rmt_clock) {
+                    Ok(encoder) => Box::new(encoder),
+                    Err(e) => {
+                        rmt_driver_uninstall(config.channel);
+                        anyhow::bail!("clock divider {}: {}", led_config.clk_div, e);
+                    }
+                };
+            let installed = esp!(rmt_translator_init(config.channel, Some(ws2812_to_rmt)))
+                .and_then(|_| {
+                    esp!(rmt_translator_set_context(
+                        config.channel,
+                        &*encoder as *const Encoder as *mut c_void,
+                    ))
+                });
+            if let Err(e) = installed {
+                rmt_driver_uninstall(config.channel);
+                return Err(e.into());
+            }
+            encoder
+        };
+
+        Ok(Self {
+            config,
+            led_config,
+            pixels: vec![RGB8::default(); led_config.pixel_count],
+            correction: led_config.correction,
+            corrected: vec![RGB8::default(); led_config.pixel_count],
+            data: Vec::with_capacity(
+                led_config.pixel_count * led_config.color_order.bytes_per_pixel(),
+            ),
+            last_frame_end: None,
+            encoder,
+        })
+    }
 
-            let ratio = rmt_clock as f64 / 1e9;
+    pub fn pixel_count(&self) -> usize {
+        self.pixels.len()
+    }
 
-            WS_CONFIG = Some(Ws2812Config {
-                t0h_ticks: (ratio * WS2812_T0H_NS as f64) as _,
-                t0l_ticks: (ratio * WS2812_T0L_NS as f64) as _,
-                t1h_ticks: (ratio * WS2812_T1H_NS as f64) as _,
-                t1l_ticks: (ratio * WS2812_T1L_NS as f64) as _,
-            });
+    /// Sets all pixels to `color`.
+    pub fn set_pixel(&mut self, color: RGB8) -> anyhow::Result<()> {
+        self.pixels.fill(color);
+        self.show()
+    }
 
-            esp!(rmt_translator_init(config.channel, Some(ws2812_to_rmt)))?;
+    /// Sets the first `pixels.len()` pixels, turns off the others and sends the frame.
+    pub fn set_pixels(&mut self, pixels: &[RGB8]) -> anyhow::Result<()> {
+        if pixels.len() > self.pixels.len() {
+            anyhow::bail!(
+                "got {} pixels, but the strip only has {}",
+                pixels.len(),
+                self.pixels.len()
+            );
         }
+        self.pixels[..pixels.len()].copy_from_slice(pixels);
+        self.pixels[pixels.len()..].fill(RGB8::default());
+        self.show()
+    }
 
-        Ok(Self { config })
+    /// The frame buffer. Changes are sent with `show`.
+    pub fn pixels_mut(&mut self) -> &mut [RGB8] {
+        &mut self.pixels
     }
 
-    pub fn set_pixel(&mut self, color: RGB8) -> anyhow::Result<()> {
-        let timeout_ms = 1;
+    pub fn correction(&self) -> ColorCorrection {
+        self.correction
+    }
+
+    /// Changes the correction applied from the next frame on.
+    pub fn set_correction(&mut self, correction: ColorCorrection) {
+        self.correction = correction;
+    }
+
+    /// Sends the frame buffer to the LEDs, after color correction.
+    pub fn show(&mut self) -> anyhow::Result<()> {
+        self.correction.apply(&self.pixels, &mut self.corrected);
+
+        self.data.clear();
+        let color_order = self.encoder.color_order();
+        for pixel in &self.corrected {
+            color_order.extend(*pixel, 0, &mut self.data);
+        }
+
+        // the LEDs only take a new frame once the previous one has latched
+        if let Some(last_frame_end) = self.last_frame_end {
+            let since_last_frame = last_frame_end.elapsed();
+            let latch = self.encoder.latch();
+            if since_last_frame < latch {
+                thread::sleep(latch - since_last_frame);
+            }
+        }
+
+        // no supported chip takes longer than 2µs per bit, plus a tick of slack
+        let frame_ms = (self.data.len() as u32 * 8 * 2000) / 1_000_000;
+        let timeout_ms = frame_ms + 1;
         unsafe {
             esp!(rmt_write_sample(
                 self.config.channel,
-                &[color.g, color.r, color.b] as *const u8, // WS2812 expects GRB, not RGB
-                3,
+                self.data.as_ptr(),
+                self.data.len() as size_t,
                 true,
             ))?;
             esp!(rmt_wait_tx_done(
                 self.config.channel,
-                (timeout_ms as u32 * FREERTOS_HZ) / 1000,
+                (timeout_ms * FREERTOS_HZ) / 1000,
             ))?;
         }
+        self.last_frame_end = Some(Instant::now());
 
         Ok(())
     }
 }
+
+impl Drop for WS2812RMT {
+    fn drop(&mut self) {
+        unsafe {
+            rmt_driver_uninstall(self.config.channel);
+        }
+        release_rmt_blocks(&self.led_config);
+    }
+}
+
+/// Lets effects from the `smart-leds` ecosystem draw on the LEDs:
+///
+/// ```ignore
+/// led.write(smart_leds::gamma(colors.iter().cloned()))?;
+/// ```
+///
+/// Code written against `SmartLedsWrite` can be tested with a mock instead of a `WS2812RMT`.
+impl SmartLedsWrite for WS2812RMT {
+    type Error = anyhow::Error;
+    type Color = RGB8;
+
+    /// Works like `set_pixels`: pixels the iterator doesn't reach are turned off.
+    fn write<T, I>(&mut self, iterator: T) -> Result<(), Self::Error>
+    where
+        T: Iterator<Item = I>,
+        I: Into<Self::Color>,
+    {
+        // one more than fits is enough to tell it's too many, before touching the frame
+        let len = self.pixels.len();
+        let pixels: Vec<RGB8> = iterator.take(len + 1).map(Into::into).collect();
+        if pixels.len() > len {
+            anyhow::bail!("got more pixels than the strip has ({})", len);
+        }
+        self.set_pixels(&pixels)
+    }
+}
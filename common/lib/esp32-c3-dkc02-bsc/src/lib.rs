@@ -1,3 +1,8 @@
+pub mod effects;
+pub mod fault;
 pub mod led;
+pub mod provisioning;
+pub mod sensors;
+pub mod status;
 pub mod temp_sensor;
 pub mod wifi;
@@ -0,0 +1,268 @@
+//! Lets Wi-Fi and MQTT settings be changed without rebuilding and reflashing.
+//!
+//! The settings are stored in NVS. On the first start, the defaults the app
+//! passes in (e.g. from `cfg.toml`) are stored, if they name a network. If
+//! there are no settings at all, or the BOOT button is pressed while the app
+//! starts, the board opens an access point of its own named `esp-rs-XXXX`
+//! instead. Connecting to it and opening `http://192.168.71.1` shows a form
+//! for the settings; once they're saved, the board restarts and connects to
+//! the configured network:
+//!
+//! ```ignore
+//! let settings = provisioning::settings_or_portal(&Settings::default())?;
+//! let _wifi = wifi(&settings.wifi_ssid, &settings.wifi_psk)?;
+//! ```
+//!
+//! The form is served over plain HTTP, so anyone on the access point can
+//! read the passwords entered in it. The access point is therefore protected
+//! with WPA2 and a password of its own, made up the first time the portal
+//! opens, stored in NVS and printed to the log every time it opens.
+//!
+//! The BOOT button (GPIO9) is also a strapping pin: holding it down while
+//! the board resets starts the ROM bootloader instead of the app, so press it
+//! right after the reset.
+
+use std::convert::Infallible;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use embedded_svc::{
+    http::{
+        server::{registry::Registry, Request, Response, ResponseWrite},
+        Method,
+    },
+    io::{Read, Write},
+    storage::RawStorage,
+    wifi::{self, AccessPointConfiguration, AuthMethod, Wifi as _},
+};
+use esp_idf_svc::{
+    http::server::{Configuration, EspHttpServer},
+    netif::EspNetifStack,
+    nvs::EspDefaultNvs,
+    nvs_storage::EspNvsStorage,
+    sysloop::EspSysLoopStack,
+    wifi::EspWifi,
+};
+use esp_idf_sys::{self as sys, esp};
+use log::info;
+pub use provisioning_form::Settings;
+use provisioning_form::{access_point_password, escape, form_html, templated, PASSWORD_LEN};
+
+use crate::status::{self, Status};
+
+/// How long after startup a press of the BOOT button opens the portal
+pub const BUTTON_WINDOW: Duration = Duration::from_secs(1);
+
+/// Where the form is served, the default address of the access point
+pub const PORTAL_ADDRESS: &str = "192.168.71.1";
+
+const BOOT_BUTTON: sys::gpio_num_t = 9;
+const NAMESPACE: &str = "provisioning";
+const MAX_FORM_SIZE: usize = 1024;
+
+/// `None` if nothing has been saved yet
+fn load(storage: &EspNvsStorage) -> anyhow::Result<Option<Settings>> {
+    let wifi_ssid = match get_string(storage, "wifi_ssid")? {
+        Some(ssid) => ssid,
+        None => return Ok(None),
+    };
+    let mut port = [0; 2];
+    let mut tls = [0; 1];
+    Ok(Some(Settings {
+        wifi_ssid,
+        wifi_psk: get_string(storage, "wifi_psk")?.unwrap_or_default(),
+        mqtt_host: get_string(storage, "mqtt_host")?.unwrap_or_default(),
+        mqtt_port: match storage.get_raw("mqtt_port", &mut port)? {
+            Some(&[high, low]) => u16::from_be_bytes([high, low]),
+            _ => Settings::default().mqtt_port,
+        },
+        mqtt_user: get_string(storage, "mqtt_user")?.unwrap_or_default(),
+        mqtt_pass: get_string(storage, "mqtt_pass")?.unwrap_or_default(),
+        mqtt_tls: matches!(storage.get_raw("mqtt_tls", &mut tls)?, Some(&[1])),
+    }))
+}
+
+fn save(storage: &mut EspNvsStorage, settings: &Settings) -> anyhow::Result<()> {
+    // the name goes last: it's what `load` looks for
+    storage.put_raw("wifi_psk", settings.wifi_psk.as_bytes())?;
+    storage.put_raw("mqtt_host", settings.mqtt_host.as_bytes())?;
+    storage.put_raw("mqtt_port", &settings.mqtt_port.to_be_bytes())?;
+    storage.put_raw("mqtt_user", settings.mqtt_user.as_bytes())?;
+    storage.put_raw("mqtt_pass", settings.mqtt_pass.as_bytes())?;
+    storage.put_raw("mqtt_tls", &[settings.mqtt_tls as u8])?;
+    storage.put_raw("wifi_ssid", settings.wifi_ssid.as_bytes())?;
+    Ok(())
+}
+
+fn get_string(storage: &EspNvsStorage, key: &str) -> anyhow::Result<Option<String>> {
+    let len = match storage.len(key)? {
+        Some(len) => len,
+        None => return Ok(None),
+    };
+    let mut buf = vec![0; len];
+    match storage.get_raw(key, &mut buf)? {
+        Some(value) => Ok(Some(String::from_utf8(value.to_vec())?)),
+        None => Ok(None),
+    }
+}
+
+/// Returns the stored settings, or runs the portal, which restarts the board
+/// once settings have been saved.
+///
+/// If nothing has been stored yet, `defaults` (e.g. from `cfg.toml`) are
+/// stored and used if they name a network, and otherwise fill in the form.
+pub fn settings_or_portal(defaults: &Settings) -> anyhow::Result<Settings> {
+    let button = button_pressed(BUTTON_WINDOW)?;
+
+    let nvs = Arc::new(EspDefaultNvs::new()?);
+    let mut storage = EspNvsStorage::new_default(nvs.clone(), NAMESPACE, true)?;
+    let prefill = match load(&storage)? {
+        Some(settings) if !button => return Ok(settings),
+        None if !button && !defaults.wifi_ssid.is_empty() => {
+            info!("no settings stored, storing the defaults");
+            save(&mut storage, defaults)?;
+            return Ok(defaults.clone());
+        }
+        Some(settings) => {
+            info!("BOOT button pressed, starting the provisioning portal");
+            settings
+        }
+        None => {
+            info!("no settings stored, starting the provisioning portal");
+            defaults.clone()
+        }
+    };
+
+    match portal(nvs, storage, &prefill)? {}
+}
+
+/// Whether the BOOT button is pressed within `window`. Returns as soon as it is.
+pub fn button_pressed(window: Duration) -> anyhow::Result<bool> {
+    unsafe {
+        esp!(sys::gpio_reset_pin(BOOT_BUTTON))?;
+        esp!(sys::gpio_set_direction(
+            BOOT_BUTTON,
+            sys::gpio_mode_t_GPIO_MODE_INPUT
+        ))?;
+        esp!(sys::gpio_set_pull_mode(
+            BOOT_BUTTON,
+            sys::gpio_pull_mode_t_GPIO_PULLUP_ONLY
+        ))?;
+    }
+
+    let start = Instant::now();
+    while start.elapsed() < window {
+        // pressed pulls the pin low
+        if unsafe { sys::gpio_get_level(BOOT_BUTTON) } == 0 {
+            return Ok(true);
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    Ok(false)
+}
+
+/// Serves the form on the access point until settings are submitted, then
+/// saves them and restarts.
+fn portal(
+    nvs: Arc<EspDefaultNvs>,
+    mut storage: EspNvsStorage,
+    prefill: &Settings,
+) -> anyhow::Result<Infallible> {
+    let netif_stack = Arc::new(EspNetifStack::new()?);
+    let sys_loop_stack = Arc::new(EspSysLoopStack::new()?);
+    let mut wifi = EspWifi::new(netif_stack, sys_loop_stack, nvs.clone())?;
+
+    let ssid = access_point_name()?;
+    let password = portal_password(&mut storage)?;
+    wifi.set_configuration(&wifi::Configuration::AccessPoint(
+        AccessPointConfiguration {
+            ssid: ssid.clone(),
+            auth_method: AuthMethod::WPA2Personal,
+            password: password.clone(),
+            ..Default::default()
+        },
+    ))?;
+    status::report(Status::Provisioning);
+    info!(
+        "connect to the Wi-Fi {} with password {} and open http://{}",
+        ssid, password, PORTAL_ADDRESS
+    );
+
+    let (sender, receiver) = mpsc::channel();
+    let sender = Mutex::new(sender);
+
+    let mut server = EspHttpServer::new(&Configuration::default())?;
+    let form = form_html(prefill, None);
+    server.set_inline_handler("/", Method::Get, move |request, response| {
+        let mut writer = response.into_writer(request)?;
+        writer.do_write_all(form.as_bytes())?;
+        writer.complete()
+    })?;
+    let prefill = prefill.clone();
+    server.set_inline_handler("/", Method::Post, move |mut request, response| {
+        let body = read_form(&mut request.reader())?;
+        let html = match body.and_then(|body| Settings::from_form(&body, &prefill)) {
+            Ok(settings) => {
+                let html = templated(format!(
+                    "<p>Saved. Restarting to connect to {}…</p>",
+                    escape(&settings.wifi_ssid)
+                ));
+                // only fails once the portal is done anyway
+                let _ = sender.lock().unwrap().send(settings);
+                html
+            }
+            Err(e) => form_html(&prefill, Some(&e.to_string())),
+        };
+        let mut writer = response.into_writer(request)?;
+        writer.do_write_all(html.as_bytes())?;
+        writer.complete()
+    })?;
+
+    let settings = receiver.recv()?;
+    save(&mut storage, &settings)?;
+    info!("settings saved, restarting");
+    // lets the response reach the browser
+    thread::sleep(Duration::from_secs(1));
+    drop(server);
+    drop(wifi);
+
+    unsafe { sys::esp_restart() }
+}
+
+/// The request body, or an error to show if it's too large for a form
+fn read_form<R: Read>(reader: &mut R) -> Result<anyhow::Result<String>, R::Error> {
+    let mut body = Vec::new();
+    let mut buf = [0; 128];
+    loop {
+        let read = reader.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        if body.len() + read > MAX_FORM_SIZE {
+            return Ok(Err(anyhow::anyhow!("the form data is too large")));
+        }
+        body.extend_from_slice(&buf[..read]);
+    }
+    Ok(String::from_utf8(body).map_err(Into::into))
+}
+
+/// The access point's password, made up and stored the first time
+fn portal_password(storage: &mut EspNvsStorage) -> anyhow::Result<String> {
+    if let Some(password) = get_string(storage, "portal_psk")? {
+        return Ok(password);
+    }
+    let mut random = [0u8; PASSWORD_LEN];
+    unsafe { sys::esp_fill_random(random.as_mut_ptr() as *mut _, random.len() as _) };
+    let password = access_point_password(random);
+    storage.put_raw("portal_psk", password.as_bytes())?;
+    Ok(password)
+}
+
+/// `esp-rs-` and the end of the MAC address, so boards next to each other can be told apart
+fn access_point_name() -> anyhow::Result<String> {
+    let mut mac = [0u8; 6];
+    esp!(unsafe { sys::esp_efuse_mac_get_default(mac.as_mut_ptr()) })?;
+    Ok(format!("esp-rs-{:02x}{:02x}", mac[4], mac[5]))
+}
@@ -0,0 +1,221 @@
+//! One API for the sensors on and around the board.
+//!
+//! Each kind of measurement has a trait returning a value with its unit in
+//! the type, so code that publishes telemetry can take any source:
+//!
+//! ```ignore
+//! fn publish(source: &mut impl TemperatureSource) -> anyhow::Result<()> {
+//!     let temperature = source.read_temperature().map_err(|e| anyhow!("{:?}", e))?;
+//!     client.publish(topic, QoS::AtLeastOnce, false, &temperature.0.to_be_bytes() as &[u8])?;
+//!     Ok(())
+//! }
+//! ```
+//!
+//! | Driver             | Temperature | Humidity | Angular rate | Acceleration |
+//! |--------------------|-------------|----------|--------------|--------------|
+//! | `BoardTempSensor`  | ✓           |          |              |              |
+//! | `SharedTempSensor` | ✓           |          |              |              |
+//! | SHTC3 (`shtcx`)    | ✓           | ✓        |              |              |
+//! | `IMC42670P`        | ✓ (die)     |          | ✓            | ✓            |
+//!
+//! For an SHTC3, `read_climate` takes both temperature and humidity from the
+//! same measurement.
+
+use std::fmt;
+
+use embedded_hal::blocking::i2c;
+use esp_idf_hal::delay::Ets;
+use imc42670p::IMC42670P;
+use shtcx::{sensor_class::ShtC3, PowerMode, ShtCx};
+
+use crate::temp_sensor::{BoardTempSensor, BorrowedRegisters, SharedTempSensor};
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub struct Celsius(pub f32);
+
+/// Relative humidity, in percent
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub struct RelativeHumidity(pub f32);
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub struct DegreesPerSecond(pub f32);
+
+/// Acceleration in multiples of standard gravity, 9.81 m/s²
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub struct StandardGravity(pub f32);
+
+/// A value for each of the three axes of a sensor
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Axes<T> {
+    pub x: T,
+    pub y: T,
+    pub z: T,
+}
+
+impl fmt::Display for Celsius {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.1}℃", self.0)
+    }
+}
+
+impl fmt::Display for RelativeHumidity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.1}%", self.0)
+    }
+}
+
+impl fmt::Display for DegreesPerSecond {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.2}°/s", self.0)
+    }
+}
+
+impl fmt::Display for StandardGravity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.3}g", self.0)
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for Axes<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "x: {} y: {} z: {}", self.x, self.y, self.z)
+    }
+}
+
+pub trait TemperatureSource {
+    type Error: fmt::Debug;
+
+    fn read_temperature(&mut self) -> Result<Celsius, Self::Error>;
+}
+
+pub trait HumiditySource {
+    type Error: fmt::Debug;
+
+    fn read_humidity(&mut self) -> Result<RelativeHumidity, Self::Error>;
+}
+
+pub trait AngularRateSource {
+    type Error: fmt::Debug;
+
+    fn read_angular_rate(&mut self) -> Result<Axes<DegreesPerSecond>, Self::Error>;
+}
+
+pub trait AccelerationSource {
+    type Error: fmt::Debug;
+
+    fn read_acceleration(&mut self) -> Result<Axes<StandardGravity>, Self::Error>;
+}
+
+/// Fails for a sensor made with `BoardTempSensor::new`.
+impl TemperatureSource for BoardTempSensor {
+    type Error = BorrowedRegisters;
+
+    fn read_temperature(&mut self) -> Result<Celsius, Self::Error> {
+        self.try_read_owning_peripherals().map(Celsius)
+    }
+}
+
+/// Fails for a sensor made with `BoardTempSensor::new`.
+impl TemperatureSource for SharedTempSensor {
+    type Error = BorrowedRegisters;
+
+    fn read_temperature(&mut self) -> Result<Celsius, Self::Error> {
+        self.lock().try_read_owning_peripherals().map(Celsius)
+    }
+}
+
+/// Temperature and humidity from a single SHTC3 measurement, in normal power
+/// mode, waiting for the result.
+pub fn read_climate<I2C, E>(
+    sht: &mut ShtCx<ShtC3, I2C>,
+) -> Result<(Celsius, RelativeHumidity), shtcx::Error<E>>
+where
+    I2C: i2c::Read<Error = E> + i2c::Write<Error = E>,
+    E: fmt::Debug,
+{
+    let measurement = sht.measure(PowerMode::NormalMode, &mut Ets)?;
+    Ok((
+        Celsius(measurement.temperature.as_degrees_celsius()),
+        RelativeHumidity(measurement.humidity.as_percent()),
+    ))
+}
+
+/// Measures in normal power mode, waiting for the result. Every reading is a
+/// measurement of its own, see `read_climate` for both values of one.
+impl<I2C, E> TemperatureSource for ShtCx<ShtC3, I2C>
+where
+    I2C: i2c::Read<Error = E> + i2c::Write<Error = E>,
+    E: fmt::Debug,
+{
+    type Error = shtcx::Error<E>;
+
+    fn read_temperature(&mut self) -> Result<Celsius, Self::Error> {
+        read_climate(self).map(|(temperature, _)| temperature)
+    }
+}
+
+/// Measures in normal power mode, waiting for the result. Every reading is a
+/// measurement of its own, see `read_climate` for both values of one.
+impl<I2C, E> HumiditySource for ShtCx<ShtC3, I2C>
+where
+    I2C: i2c::Read<Error = E> + i2c::Write<Error = E>,
+    E: fmt::Debug,
+{
+    type Error = shtcx::Error<E>;
+
+    fn read_humidity(&mut self) -> Result<RelativeHumidity, Self::Error> {
+        read_climate(self).map(|(_, humidity)| humidity)
+    }
+}
+
+/// The die temperature. Needs the gyroscope or accelerometer to be running.
+impl<I2C, E> TemperatureSource for IMC42670P<I2C>
+where
+    I2C: i2c::WriteRead<Error = E> + i2c::Write<Error = E>,
+    E: fmt::Debug,
+{
+    type Error = E;
+
+    fn read_temperature(&mut self) -> Result<Celsius, Self::Error> {
+        let raw = IMC42670P::read_temperature(self)?;
+        Ok(Celsius(imc42670p::temperature_celsius(raw)))
+    }
+}
+
+/// Needs the gyroscope to be running, see `IMC42670P::gyro_ln`.
+impl<I2C, E> AngularRateSource for IMC42670P<I2C>
+where
+    I2C: i2c::WriteRead<Error = E> + i2c::Write<Error = E>,
+    E: fmt::Debug,
+{
+    type Error = E;
+
+    fn read_angular_rate(&mut self) -> Result<Axes<DegreesPerSecond>, Self::Error> {
+        let data = self.read_gyro()?;
+        let scale = |raw| DegreesPerSecond(imc42670p::gyro_dps(raw));
+        Ok(Axes {
+            x: scale(data.x),
+            y: scale(data.y),
+            z: scale(data.z),
+        })
+    }
+}
+
+/// Needs the accelerometer to be running, see `IMC42670P::accel_ln`.
+impl<I2C, E> AccelerationSource for IMC42670P<I2C>
+where
+    I2C: i2c::WriteRead<Error = E> + i2c::Write<Error = E>,
+    E: fmt::Debug,
+{
+    type Error = E;
+
+    fn read_acceleration(&mut self) -> Result<Axes<StandardGravity>, Self::Error> {
+        let data = self.read_accel()?;
+        let scale = |raw| StandardGravity(imc42670p::accel_g(raw));
+        Ok(Axes {
+            x: scale(data.x),
+            y: scale(data.y),
+            z: scale(data.z),
+        })
+    }
+}
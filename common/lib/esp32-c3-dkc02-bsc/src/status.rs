@@ -0,0 +1,147 @@
+//! Shows what the board is up to on the board LED.
+//!
+//! An app installs the indicator once, then the BSC (e.g. `wifi`) and the
+//! app itself report their state with `status::report`:
+//!
+//! ```ignore
+//! status::install(StatusIndicator::start(WS2812RMT::new()?)?)?;
+//! // ...
+//! status::report(Status::MqttConnected);
+//! ```
+//!
+//! Without an installed indicator, reports are only logged, so apps that use
+//! the LED for something else can still call `report`.
+//!
+//! | Status             | LED                             |
+//! |--------------------|---------------------------------|
+//! | `Booting`          | yellow                          |
+//! | `WifiConnecting`   | blue, blinking fast             |
+//! | `WifiUp`           | blue, breathing                 |
+//! | `MqttConnected`    | green, breathing                |
+//! | `CommandReceived`  | short white flash               |
+//! | `Error`            | red                             |
+//! | `Ota`              | purple, chasing along the strip |
+//! | `Provisioning`     | white, breathing                |
+//! | `Fault(code)`      | red, blinking the fault code    |
+
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use log::info;
+
+use crate::effects::{Effect, Effects};
+use crate::fault::FaultCode;
+use crate::led::{RGB8, WS2812RMT};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Booting,
+    WifiConnecting,
+    WifiUp,
+    MqttConnected,
+    /// Only flashes, then the previous status is shown again.
+    CommandReceived,
+    Error,
+    /// Never reported by the BSC, which doesn't update firmware: apps that
+    /// do report it themselves while an update is downloaded.
+    Ota,
+    /// The board is waiting for its settings, see `provisioning`.
+    Provisioning,
+    /// Shown by `fault` before it restarts the board.
+    Fault(FaultCode),
+}
+
+impl Status {
+    fn effect(self) -> Option<Effect> {
+        let effect = match self {
+            Status::Booting => Effect::Solid(RGB8::new(50, 50, 0)),
+            Status::WifiConnecting => Effect::Blink {
+                color: RGB8::new(0, 0, 50),
+                on: Duration::from_millis(150),
+                off: Duration::from_millis(150),
+            },
+            Status::WifiUp => Effect::Breathe {
+                color: RGB8::new(0, 0, 50),
+                period: Duration::from_secs(2),
+            },
+            Status::MqttConnected => Effect::Breathe {
+                color: RGB8::new(0, 50, 0),
+                period: Duration::from_secs(3),
+            },
+            Status::CommandReceived => return None,
+            Status::Error => Effect::Solid(RGB8::new(50, 0, 0)),
+            Status::Ota => Effect::Chase {
+                color: RGB8::new(40, 0, 50),
+                step: Duration::from_millis(100),
+            },
+            Status::Provisioning => Effect::Breathe {
+                color: RGB8::new(30, 30, 30),
+                period: Duration::from_secs(2),
+            },
+            Status::Fault(code) => Effect::BlinkCode {
+                color: FaultCode::COLOR,
+                code: code.blinks(),
+            },
+        };
+        Some(effect)
+    }
+}
+
+/// Maps `Status` to LED effects.
+#[derive(Clone)]
+pub struct StatusIndicator {
+    effects: Effects,
+}
+
+impl StatusIndicator {
+    /// Takes over `led`, showing `Status::Booting`.
+    pub fn start(led: WS2812RMT) -> anyhow::Result<Self> {
+        Ok(Self::new(Effects::start(led)?))
+    }
+
+    pub fn new(effects: Effects) -> Self {
+        let indicator = Self { effects };
+        indicator.show(Status::Booting);
+        indicator
+    }
+
+    pub fn show(&self, status: Status) {
+        match status.effect() {
+            Some(effect) => self.effects.set(effect),
+            None => self
+                .effects
+                .flash(RGB8::new(50, 50, 50), Duration::from_millis(100)),
+        }
+    }
+}
+
+/// Set once by `install` and never freed, like a logger
+static INDICATOR: AtomicPtr<Mutex<StatusIndicator>> = AtomicPtr::new(null_mut());
+
+/// Makes `report` show the status on `indicator`. Can only be called once.
+pub fn install(indicator: StatusIndicator) -> anyhow::Result<()> {
+    let indicator = Box::into_raw(Box::new(Mutex::new(indicator)));
+    let installed =
+        INDICATOR.compare_exchange(null_mut(), indicator, Ordering::SeqCst, Ordering::SeqCst);
+    if installed.is_err() {
+        // never published, so still ours
+        drop(unsafe { Box::from_raw(indicator) });
+        anyhow::bail!("a status indicator is already installed");
+    }
+    Ok(())
+}
+
+pub fn is_installed() -> bool {
+    !INDICATOR.load(Ordering::SeqCst).is_null()
+}
+
+pub fn report(status: Status) {
+    info!("status: {:?}", status);
+    let indicator = INDICATOR.load(Ordering::SeqCst);
+    // only ever set to a leaked box by `install`
+    if let Some(indicator) = unsafe { indicator.as_ref() } {
+        indicator.lock().unwrap().show(status);
+    }
+}
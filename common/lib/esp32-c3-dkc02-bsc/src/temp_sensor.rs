@@ -1,52 +1,43 @@
-use esp32c3::{Peripherals, APB_SARADC};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use esp32c3::{apb_saradc, Peripherals, APB_SARADC};
 use log::info;
+use tsens::efuse;
+use tsens::filter::Filter;
+pub use tsens::filter::{FilterConfig, Smoothing, Statistics};
+pub use tsens::range::{AutoRange, DacOffset};
 
 const _XPD_WAIT_DEFAULT: u16 = 0xFF; /* Set wait cycle time(8MHz) from power up to reset enable. */
-const ADC_FACTOR: f32 = 0.4386;
-const DAC_FACTOR: f32 = 27.88;
 const _SYS_OFFSET: f32 = 20.52;
+/// Time between the conversions of an oversampled reading, at least one
+/// conversion period
+const CONVERSION_INTERVAL_US: u32 = 100;
 
-#[allow(unused)]
-enum DacOffset {
-    L0 = 5,  /*< offset = -2, measure range: 50℃ ~ 125℃, error < 3℃. */
-    L1 = 7,  /*< offset = -1, measure range: 20℃ ~ 100℃, error < 2℃. */
-    L2 = 15, /*< offset =  0, measure range:-10℃ ~  80℃, error < 1℃. */
-    L3 = 11, /*< offset =  1, measure range:-30℃ ~  50℃, error < 2℃. */
-    L4 = 10, /*< offset =  2, measure range:-40℃ ~  20℃, error < 3℃. */
-}
+// analog I2C register that selects the measurement range, from `regi2c_saradc.h`
+const I2C_SAR_ADC: u8 = 0x69;
+const I2C_SAR_ADC_HOSTID: u8 = 0;
+const I2C_SARADC_TSENS_DAC: u8 = 0x6;
+const I2C_SARADC_TSENS_DAC_MSB: u8 = 3;
+const I2C_SARADC_TSENS_DAC_LSB: u8 = 0;
 
-impl Default for DacOffset {
-    fn default() -> Self {
-        DacOffset::L2
-    }
-}
+// from `regi2c_ctrl.h`
+const ANA_CONFIG_REG: *mut u32 = 0x6000_e044 as _;
+const ANA_CONFIG2_REG: *mut u32 = 0x6000_e048 as _;
+const ANA_I2C_SAR_FORCE_PD: u32 = 1 << 18;
+const ANA_I2C_SAR_FORCE_PU: u32 = 1 << 16;
 
-impl DacOffset {
-    fn offset(&self) -> i8 {
-        match self {
-            DacOffset::L0 => -2,
-            DacOffset::L1 => -1,
-            DacOffset::L2 => 0,
-            DacOffset::L3 => 1,
-            DacOffset::L4 => 2,
-        }
-    }
+extern "C" {
+    // declared in `regi2c_ctrl.h`: the ROM function, wrapped in the critical
+    // section that ESP-IDF uses for every access to the analog I2C bus
+    fn regi2c_ctrl_write_reg_mask(block: u8, host_id: u8, reg_add: u8, msb: u8, lsb: u8, data: u8);
 }
 
-#[allow(unused)]
 struct SensorConfig {
     dac_offset: DacOffset,
     clock_divider: u8,
-}
-
-#[allow(unused)]
-impl SensorConfig {
-    fn new(dac_offset: DacOffset, clock_divider: u8) -> Self {
-        Self {
-            dac_offset,
-            clock_divider,
-        }
-    }
+    auto_range: Option<AutoRange>,
 }
 
 impl Default for SensorConfig {
@@ -54,40 +45,125 @@ impl Default for SensorConfig {
         Self {
             clock_divider: 6,
             dac_offset: Default::default(),
+            auto_range: None,
         }
     }
 }
 
+static REGISTERS_TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// Exclusive access to the registers of the temperature sensor, without
+/// taking all of `esp32c3::Peripherals`. This leaves them to apps that use
+/// `esp_idf_hal::peripherals::Peripherals` for I²C, GPIO and so on, which
+/// has no temperature sensor of its own.
+///
+/// Only one exists at a time; dropping it allows taking it again. Every
+/// `BoardTempSensor` holds one, however it was made.
+pub struct TempSensorRegisters {
+    _private: (),
+}
+
+impl TempSensorRegisters {
+    /// `None` if a `BoardTempSensor` is still around
+    pub fn take() -> Option<Self> {
+        REGISTERS_TAKEN
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .ok()
+            .map(|_| Self { _private: () })
+    }
+}
+
+impl Drop for TempSensorRegisters {
+    fn drop(&mut self) {
+        REGISTERS_TAKEN.store(false, Ordering::SeqCst);
+    }
+}
+
+enum Registers {
+    Owned(Peripherals),
+    Scoped,
+    /// Passed to every `read`
+    Borrowed,
+}
+
 pub struct BoardTempSensor {
     config: SensorConfig,
     efuse_calibration: f32,
-    peripherals: Option<Peripherals>,
+    filter: Filter,
+    registers: Registers,
+    _taken: TempSensorRegisters,
 }
 
 impl BoardTempSensor {
+    /// Panics if `esp32c3::Peripherals` have already been taken, or another
+    /// `BoardTempSensor` exists. Prefer `take`.
     pub fn new_taking_peripherals() -> Self {
         let mut peripherals = Peripherals::take().unwrap();
-        let efuse_calibration = Self::common_init(&mut peripherals);
-        Self {
-            config: Default::default(),
-            efuse_calibration,
-            peripherals: Some(peripherals),
-        }
+        let mut sensor = Self::init(
+            &mut peripherals,
+            Registers::Borrowed,
+            Self::take_registers(),
+        );
+        sensor.registers = Registers::Owned(peripherals);
+        sensor
     }
+
+    /// Every reading has to be made with `read`, passing `peripherals.APB_SARADC`.
+    ///
+    /// Panics if another `BoardTempSensor` exists.
     pub fn new(peripherals: &mut Peripherals) -> Self {
-        let efuse_calibration = Self::common_init(peripherals);
+        Self::init(peripherals, Registers::Borrowed, Self::take_registers())
+    }
+
+    /// Takes only the temperature sensor registers, so this works next to
+    /// `esp_idf_hal::peripherals::Peripherals`.
+    pub fn take() -> anyhow::Result<Self> {
+        match TempSensorRegisters::take() {
+            Some(registers) => Ok(Self::with_registers(registers)),
+            None => anyhow::bail!("the temperature sensor is already in use"),
+        }
+    }
+
+    pub fn with_registers(registers: TempSensorRegisters) -> Self {
+        // `registers` proves nobody else uses the temperature sensor. Of the
+        // other peripherals, the eFuses are only read, and the TSENS clock is
+        // enabled through ESP-IDF, which does that under a lock
+        let mut peripherals = unsafe { Peripherals::steal() };
+        Self::init(&mut peripherals, Registers::Scoped, registers)
+    }
+
+    /// Makes a handle that can be cloned and used from several threads,
+    /// e.g. HTTP handlers.
+    pub fn shared(self) -> SharedTempSensor {
+        SharedTempSensor(Arc::new(Mutex::new(self)))
+    }
+
+    fn take_registers() -> TempSensorRegisters {
+        TempSensorRegisters::take().expect("the temperature sensor is already in use")
+    }
+
+    fn init(
+        peripherals: &mut Peripherals,
+        registers: Registers,
+        taken: TempSensorRegisters,
+    ) -> Self {
+        let config = SensorConfig::default();
+        let efuse_calibration = Self::common_init(peripherals, &config);
         Self {
-            config: Default::default(),
+            config,
             efuse_calibration,
-            peripherals: None,
+            filter: Filter::new(Default::default()),
+            registers,
+            _taken: taken,
         }
     }
-    fn common_init(peripherals: &mut Peripherals) -> f32 {
-        // enable TSENS clock
-        peripherals
-            .SYSTEM
-            .perip_clk_en1
-            .modify(|_r, w| w.tsens_clk_en().set_bit());
+
+    fn common_init(peripherals: &mut Peripherals, config: &SensorConfig) -> f32 {
+        // enable TSENS clock; `perip_clk_en1` is shared with other drivers,
+        // so leave the read-modify-write to ESP-IDF
+        unsafe {
+            esp_idf_sys::periph_module_enable(esp_idf_sys::periph_module_t_PERIPH_TEMPSENSOR_MODULE)
+        };
 
         // select XTAL clock for TSENS:
         /*
@@ -105,70 +181,195 @@ impl BoardTempSensor {
             .apb_tsens_ctrl
             .modify(|_r, w| w.tsens_pu().set_bit());
 
-        // TODO conflicting information - on the one hand,
-        /*
-        esp_efuse_table.c
-        static const esp_efuse_desc_t TEMP_CALIB[] = {
-        {EFUSE_BLK2, 131, 9}, 	 // Temperature calibration data,
-        };
-        */
-        // register is 128..160
-        // we want 131 .. 131+9
-        // -> offset 3, but python definition disagrees:
-
-        // from esptool/blob/master/espressif/efuse/esp32c3/mem_definition.py:
-        // # Name                      Category      Block Word Pos Type:len  WR_DIS RD_DIS Class         Description                Dictionary
-        // ('TEMP_SENSOR_CAL',         "calibration",   2,  4, 7,   "uint:9",   21,   None, "t_sensor",   "Temperature calibration", None),
-        //
-        // a "word" is 32 bit it seems, so that's base = 32*4 + 7 = 135, not 131
-
-        let register_contents = peripherals.EFUSE.rd_sys_part1_data4.read().bits();
-        info!("raw data: {:b}", register_contents);
-
-        // `as u8` truncates accordingly, otherwise we'd need e.g. & 0xff
-        let efuse_calibration_raw = (register_contents >> 7) as u8;
-
-        // TODO this is what the IDF C source does, but is it correct?
-        // TODO why does `mem_definition.py` say the length is 9?
-        let sign = if efuse_calibration_raw & 0b1000_0000 > 0 {
-            -1.
-        } else {
-            1.
-        };
+        peripherals
+            .APB_SARADC
+            .apb_tsens_ctrl
+            .modify(|_r, w| unsafe { w.tsens_clk_div().bits(config.clock_divider) });
+
+        // the DAC offset is set through the analog I2C bus, which needs to be powered
+        unsafe {
+            ANA_CONFIG_REG.write_volatile(ANA_CONFIG_REG.read_volatile() & !ANA_I2C_SAR_FORCE_PD);
+            ANA_CONFIG2_REG.write_volatile(ANA_CONFIG2_REG.read_volatile() | ANA_I2C_SAR_FORCE_PU);
+        }
+        write_dac_offset(config.dac_offset);
 
-        let efuse_calibration = efuse_calibration_raw as f32 / 10. * sign;
+        // eFuse block 1 says which version of calibration data block 2 holds
+        let efuse = &peripherals.EFUSE;
+        let block1 = [
+            efuse.rd_mac_spi_sys_0.read().bits(),
+            efuse.rd_mac_spi_sys_1.read().bits(),
+            efuse.rd_mac_spi_sys_2.read().bits(),
+            efuse.rd_mac_spi_sys_3.read().bits(),
+            efuse.rd_mac_spi_sys_4.read().bits(),
+            efuse.rd_mac_spi_sys_5.read().bits(),
+        ];
+        let block2 = [
+            efuse.rd_sys_part1_data0.read().bits(),
+            efuse.rd_sys_part1_data1.read().bits(),
+            efuse.rd_sys_part1_data2.read().bits(),
+            efuse.rd_sys_part1_data3.read().bits(),
+            efuse.rd_sys_part1_data4.read().bits(),
+            efuse.rd_sys_part1_data5.read().bits(),
+            efuse.rd_sys_part1_data6.read().bits(),
+            efuse.rd_sys_part1_data7.read().bits(),
+        ];
+        info!("raw data: {:b}", block2[4]);
+
+        let efuse_calibration = efuse::temp_calibration_from_blocks(&block1, &block2);
 
         info!("efuse calibration: {}", efuse_calibration);
 
         efuse_calibration
     }
 
-    fn read_impl(&self, adc: &mut APB_SARADC) -> f32 {
-        let register = adc.apb_tsens_ctrl.read();
-        let raw_value = register.tsens_out().bits();
-        let value = ADC_FACTOR * (raw_value as f32)
-            - DAC_FACTOR * self.config.dac_offset.offset() as f32
-            - self.efuse_calibration;
+    /// The range the next reading is taken in
+    pub fn range(&self) -> DacOffset {
+        self.config.dac_offset
+    }
+
+    /// Measures in `range` from now on, turning off auto-ranging.
+    pub fn set_range(&mut self, range: DacOffset) {
+        self.config.auto_range = None;
+        self.switch_range(range);
+    }
+
+    /// Picks the most accurate range after every reading, see `AutoRange`.
+    /// `None` keeps the current range from now on.
+    pub fn set_auto_range(&mut self, auto_range: Option<AutoRange>) {
+        self.config.auto_range = auto_range;
+    }
+
+    fn switch_range(&mut self, range: DacOffset) {
+        if range != self.config.dac_offset {
+            info!(
+                "temperature sensor range: {:?} ({:?}℃)",
+                range,
+                range.range()
+            );
+            write_dac_offset(range);
+            self.config.dac_offset = range;
+        }
+    }
+
+    /// Oversampling, outlier rejection and smoothing of readings.
+    /// Replacing it forgets earlier readings.
+    pub fn set_filter(&mut self, config: FilterConfig) {
+        self.filter = Filter::new(config);
+    }
+
+    /// Min, max, mean and standard deviation of the last readings before
+    /// smoothing, e.g. to publish along with the value
+    pub fn statistics(&self) -> Option<Statistics> {
+        self.filter.statistics()
+    }
+
+    /// Takes the conversions for one reading, in ℃
+    fn convert(&self, adc: &apb_saradc::RegisterBlock) -> Vec<f32> {
+        let oversampling = self.filter.config().oversampling.max(1);
+        (0..oversampling)
+            .map(|i| {
+                if i > 0 {
+                    unsafe { esp_idf_sys::ets_delay_us(CONVERSION_INTERVAL_US) };
+                }
+                let raw_value = adc.apb_tsens_ctrl.read().tsens_out().bits();
+                self.config
+                    .dac_offset
+                    .to_celsius(raw_value, self.efuse_calibration)
+            })
+            .collect()
+    }
+
+    fn filter(&mut self, mut conversions: Vec<f32>) -> f32 {
+        let value = self.filter.push(&mut conversions);
+
+        if let Some(auto_range) = self.config.auto_range {
+            self.switch_range(auto_range.next(self.config.dac_offset, value));
+        }
 
         value
     }
 
-    pub fn read(&self, adc: &mut APB_SARADC) -> f32 {
-        self.read_impl(adc)
+    pub fn read(&mut self, adc: &mut APB_SARADC) -> f32 {
+        let conversions = self.convert(adc);
+        self.filter(conversions)
     }
 
+    /// Panics for a sensor made with `new`, which has to use `read`.
     pub fn read_owning_peripherals(&mut self) -> f32 {
-        let adc = &mut self.peripherals.as_mut().unwrap().APB_SARADC;
-        let register = adc.apb_tsens_ctrl.read();
-        let raw_value = register.tsens_out().bits();
-        let value = ADC_FACTOR * (raw_value as f32)
-            - DAC_FACTOR * self.config.dac_offset.offset() as f32
-            - self.efuse_calibration;
+        self.try_read_owning_peripherals()
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
 
-        value
+    /// Like `read_owning_peripherals`, but fails for a sensor made with `new`.
+    pub fn try_read_owning_peripherals(&mut self) -> Result<f32, BorrowedRegisters> {
+        let adc: &apb_saradc::RegisterBlock = match &self.registers {
+            Registers::Owned(peripherals) => &peripherals.APB_SARADC,
+            // only reads `apb_tsens_ctrl`, which `TempSensorRegisters` hands out exclusively
+            Registers::Scoped => unsafe { &*APB_SARADC::ptr() },
+            Registers::Borrowed => return Err(BorrowedRegisters),
+        };
+        let conversions = self.convert(adc);
+        Ok(self.filter(conversions))
     }
 
+    /// Returns the `esp32c3::Peripherals` taken by `new_taking_peripherals`.
+    /// Another `BoardTempSensor` can be made afterwards.
     pub fn free(self) -> Option<Peripherals> {
-        self.peripherals
+        match self.registers {
+            Registers::Owned(peripherals) => Some(peripherals),
+            _ => None,
+        }
+    }
+}
+
+/// The sensor was made with `BoardTempSensor::new`, so it can only be read
+/// with `read`, passing the registers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BorrowedRegisters;
+
+impl fmt::Display for BorrowedRegisters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "this temperature sensor has to be read with `read`")
+    }
+}
+
+impl std::error::Error for BorrowedRegisters {}
+
+fn write_dac_offset(range: DacOffset) {
+    unsafe {
+        regi2c_ctrl_write_reg_mask(
+            I2C_SAR_ADC,
+            I2C_SAR_ADC_HOSTID,
+            I2C_SARADC_TSENS_DAC,
+            I2C_SARADC_TSENS_DAC_MSB,
+            I2C_SARADC_TSENS_DAC_LSB,
+            range.register_value(),
+        );
+        // the sensor converts all the time, so `tsens_out` may still hold a
+        // conversion with the old offset until the next one is done
+        esp_idf_sys::ets_delay_us(CONVERSION_INTERVAL_US);
+    }
+}
+
+/// A `BoardTempSensor` that can be cloned and used from several threads.
+#[derive(Clone)]
+pub struct SharedTempSensor(Arc<Mutex<BoardTempSensor>>);
+
+impl SharedTempSensor {
+    /// Panics for a sensor made with `BoardTempSensor::new`.
+    pub fn read(&self) -> f32 {
+        self.lock().read_owning_peripherals()
+    }
+
+    pub fn statistics(&self) -> Option<Statistics> {
+        self.lock().statistics()
+    }
+
+    /// For anything else, e.g. `set_filter` or `set_range`
+    pub fn lock(&self) -> std::sync::MutexGuard<'_, BoardTempSensor> {
+        // a panic while reading leaves the sensor usable
+        self.0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
     }
 }
@@ -1,92 +1,518 @@
 // based on https://github.com/ivmarkov/rust-esp32-std-demo/blob/main/src/main.rs
 
-use std::sync::Arc;
+//! Connects to an access point and stays connected.
+//!
+//! Wi-Fi and IP events from the system event loop tell how the connection is
+//! doing: it counts as up once the board has an IP address (see
+//! `wifi_connect::state`). After a drop, it reconnects after a pause of 1 s,
+//! which doubles up to 60 s while reconnecting fails and starts over once
+//! connected (see `wifi_connect::backoff`). With several known networks,
+//! `wifi_multi` picks one from what a scan finds. Other parts of an app can
+//! follow along with a `WifiWatch`:
+//!
+//! ```ignore
+//! let wifi = wifi(ssid, psk)?;
+//! let watch = wifi.watch();
+//! for state in watch.subscribe() {
+//!     if state == WifiState::Connected {
+//!         // resume publishing
+//!     }
+//! }
+//! ```
 
-use anyhow::bail;
+use std::ffi::c_void;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context};
 use embedded_svc::wifi::{
-    self, AuthMethod, ClientConfiguration, ClientConnectionStatus, ClientIpStatus, ClientStatus,
+    self, AccessPointInfo, AuthMethod, ClientConfiguration, ClientConnectionStatus, ClientStatus,
     Wifi as _,
 };
 use esp_idf_svc::{
     netif::EspNetifStack, nvs::EspDefaultNvs, sysloop::EspSysLoopStack, wifi::EspWifi,
 };
-use log::info;
+use esp_idf_sys::{self as sys, esp};
+use log::{info, warn};
+use wifi_connect::backoff::Backoff;
+use wifi_connect::candidates::candidates;
+pub use wifi_connect::candidates::Credentials;
+pub use wifi_connect::state::WifiState;
+use wifi_connect::state::{self, Attempt, Tracker};
+
+use crate::fault::FaultCode;
+use crate::status::{self, Status};
+
+/// How long `wifi` waits for the first connection to each network
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(20);
+
+struct WatchState {
+    tracker: Tracker,
+    subscribers: Vec<Sender<WifiState>>,
+}
+
+/// Follows the state of the connection, e.g. to pause MQTT or HTTP while it is down.
+#[derive(Clone)]
+pub struct WifiWatch {
+    shared: Arc<(Mutex<WatchState>, Condvar)>,
+}
+
+impl WifiWatch {
+    fn new() -> Self {
+        let state = WatchState {
+            tracker: Tracker::new(),
+            subscribers: Vec::new(),
+        };
+        Self {
+            shared: Arc::new((Mutex::new(state), Condvar::new())),
+        }
+    }
+
+    pub fn state(&self) -> WifiState {
+        self.shared.0.lock().unwrap().tracker.state()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.state() == WifiState::Connected
+    }
+
+    /// Blocks until the connection is in `state` or `timeout` has passed.
+    /// Returns whether it is in `state`.
+    pub fn wait_for(&self, state: WifiState, timeout: Duration) -> bool {
+        let (lock, changed) = &*self.shared;
+        let guard = lock.lock().unwrap();
+        let (guard, _) = changed
+            .wait_timeout_while(guard, timeout, |current| current.tracker.state() != state)
+            .unwrap();
+        guard.tracker.state() == state
+    }
+
+    /// Receives every change of state from now on.
+    pub fn subscribe(&self) -> Receiver<WifiState> {
+        let (sender, receiver) = mpsc::channel();
+        self.shared.0.lock().unwrap().subscribers.push(sender);
+        receiver
+    }
+
+    fn begin_attempt(&self) {
+        self.shared.0.lock().unwrap().tracker.begin_attempt();
+    }
+
+    /// `None` if the attempt is still going after `timeout`
+    fn wait_for_attempt(&self, timeout: Duration) -> Option<Attempt> {
+        let (lock, changed) = &*self.shared;
+        let guard = lock.lock().unwrap();
+        let (guard, _) = changed
+            .wait_timeout_while(guard, timeout, |current| {
+                current.tracker.attempt().is_none()
+            })
+            .unwrap();
+        guard.tracker.attempt()
+    }
+
+    fn handle(&self, event: state::Event) {
+        let (lock, changed) = &*self.shared;
+        let mut current = lock.lock().unwrap();
+        let new_state = current.tracker.handle(event);
+        // also wakes `wait_for_attempt` for rejections
+        changed.notify_all();
+        let state = match new_state {
+            Some(state) => state,
+            None => return,
+        };
+        info!("Wifi {:?}", state);
+        current
+            .subscribers
+            .retain(|subscriber| subscriber.send(state).is_ok());
+
+        status::report(match state {
+            WifiState::Connected => Status::WifiUp,
+            WifiState::Connecting | WifiState::Disconnected => Status::WifiConnecting,
+        });
+    }
+}
+
+/// The events the connection follows, forwarded from the system event loop
+#[derive(Debug)]
+enum Event {
+    Associated,
+    Disconnected { reason: u8 },
+    GotIp,
+    LostIp,
+    AttemptStarted,
+}
+
+/// Event base of the marker `post_attempt_started` sends
+static ATTEMPT_EVENT: &[u8] = b"BSC_WIFI_ATTEMPT\0";
+
+fn attempt_event() -> sys::esp_event_base_t {
+    ATTEMPT_EVENT.as_ptr() as sys::esp_event_base_t
+}
+
+/// Marks the start of an attempt. Going through the system event loop, the
+/// marker reaches `run` after every event the driver posted before.
+fn post_attempt_started() -> anyhow::Result<()> {
+    esp!(unsafe {
+        sys::esp_event_post(
+            attempt_event(),
+            0,
+            std::ptr::null_mut(),
+            0,
+            sys::TickType_t::MAX,
+        )
+    })?;
+    Ok(())
+}
+
+/// Forwards events to a channel until dropped
+struct EventHandlers {
+    wifi: sys::esp_event_handler_instance_t,
+    ip: sys::esp_event_handler_instance_t,
+    attempt: sys::esp_event_handler_instance_t,
+    _sender: Box<Mutex<Sender<Event>>>,
+}
+
+impl EventHandlers {
+    /// Needs the default event loop, which `EspSysLoopStack` creates.
+    fn register(sender: Sender<Event>) -> anyhow::Result<Self> {
+        let sender = Box::new(Mutex::new(sender));
+        let arg = &*sender as *const Mutex<Sender<Event>> as *mut c_void;
+        let mut handlers = Self {
+            wifi: std::ptr::null_mut(),
+            ip: std::ptr::null_mut(),
+            attempt: std::ptr::null_mut(),
+            _sender: sender,
+        };
+        // `arg` stays valid until `drop` unregisters the handlers
+        unsafe {
+            esp!(sys::esp_event_handler_instance_register(
+                sys::WIFI_EVENT,
+                sys::ESP_EVENT_ANY_ID,
+                Some(on_event),
+                arg,
+                &mut handlers.wifi,
+            ))?;
+            esp!(sys::esp_event_handler_instance_register(
+                sys::IP_EVENT,
+                sys::ESP_EVENT_ANY_ID,
+                Some(on_event),
+                arg,
+                &mut handlers.ip,
+            ))?;
+            esp!(sys::esp_event_handler_instance_register(
+                attempt_event(),
+                sys::ESP_EVENT_ANY_ID,
+                Some(on_event),
+                arg,
+                &mut handlers.attempt,
+            ))?;
+        }
+        Ok(handlers)
+    }
+}
+
+impl Drop for EventHandlers {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.wifi.is_null() {
+                sys::esp_event_handler_instance_unregister(
+                    sys::WIFI_EVENT,
+                    sys::ESP_EVENT_ANY_ID,
+                    self.wifi,
+                );
+            }
+            if !self.ip.is_null() {
+                sys::esp_event_handler_instance_unregister(
+                    sys::IP_EVENT,
+                    sys::ESP_EVENT_ANY_ID,
+                    self.ip,
+                );
+            }
+            if !self.attempt.is_null() {
+                sys::esp_event_handler_instance_unregister(
+                    attempt_event(),
+                    sys::ESP_EVENT_ANY_ID,
+                    self.attempt,
+                );
+            }
+        }
+    }
+}
+
+unsafe extern "C" fn on_event(
+    arg: *mut c_void,
+    base: sys::esp_event_base_t,
+    id: i32,
+    data: *mut c_void,
+) {
+    let event = if base == sys::WIFI_EVENT {
+        match id as u32 {
+            sys::wifi_event_t_WIFI_EVENT_STA_CONNECTED => Event::Associated,
+            sys::wifi_event_t_WIFI_EVENT_STA_DISCONNECTED => {
+                let data = &*(data as *const sys::wifi_event_sta_disconnected_t);
+                Event::Disconnected {
+                    reason: data.reason,
+                }
+            }
+            _ => return,
+        }
+    } else if base == sys::IP_EVENT {
+        match id as u32 {
+            sys::ip_event_t_IP_EVENT_STA_GOT_IP => Event::GotIp,
+            sys::ip_event_t_IP_EVENT_STA_LOST_IP => Event::LostIp,
+            _ => return,
+        }
+    } else if base == attempt_event() {
+        Event::AttemptStarted
+    } else {
+        return;
+    };
+
+    let sender = &*(arg as *const Mutex<Sender<Event>>);
+    // only fails once the connection is being dropped
+    let _ = sender.lock().unwrap().send(event);
+}
+
+/// Follows the connection, until `events` closes.
+///
+/// Once connected, every drop stops `esp_wifi`, which would otherwise keep
+/// retrying right away, and starts it again after the pause `Backoff` gives.
+/// Until then, `connect` decides when to try which network.
+fn run(events: Receiver<Event>, watch: WifiWatch, esp_wifi: Arc<Mutex<EspWifi>>) {
+    let mut backoff = Backoff::new();
+    let mut connected_once = false;
+    // when to start `esp_wifi` again, and with what
+    let mut paused: Option<(Instant, wifi::Configuration)> = None;
+    loop {
+        let resume_in = paused
+            .as_ref()
+            .map(|(resume_at, _)| resume_at.saturating_duration_since(Instant::now()));
+        let event = match resume_in {
+            Some(resume_in) => match events.recv_timeout(resume_in) {
+                Ok(event) => event,
+                Err(RecvTimeoutError::Timeout) => {
+                    let (_, configuration) = paused.take().unwrap();
+                    info!("Wifi reconnecting");
+                    if let Err(e) = esp_wifi.lock().unwrap().set_configuration(&configuration) {
+                        warn!("could not restart Wifi: {:?}", e);
+                        paused = Some((Instant::now() + backoff.next_pause(), configuration));
+                    }
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => return,
+            },
+            None => match events.recv() {
+                Ok(event) => event,
+                Err(_) => return,
+            },
+        };
+
+        watch.handle(match event {
+            Event::Associated => state::Event::Associated,
+            Event::Disconnected { reason } => {
+                warn!("Wifi disconnected (reason {})", reason);
+                // later disconnects while paused are the pause itself
+                if connected_once && paused.is_none() {
+                    paused = pause(&esp_wifi, backoff.next_pause());
+                }
+                state::Event::Disconnected {
+                    rejected: is_rejection(reason),
+                }
+            }
+            Event::GotIp => {
+                connected_once = true;
+                backoff.reset();
+                state::Event::GotIp
+            }
+            Event::LostIp => state::Event::LostIp,
+            Event::AttemptStarted => state::Event::AttemptStarted,
+        });
+    }
+}
+
+/// Stops `esp_wifi` unless its own retry already connected again.
+/// Returns when to start it again, and with what.
+fn pause(esp_wifi: &Mutex<EspWifi>, pause: Duration) -> Option<(Instant, wifi::Configuration)> {
+    let mut esp_wifi = esp_wifi.lock().unwrap();
+    if let ClientStatus::Started(ClientConnectionStatus::Connected(_)) = esp_wifi.get_status().0 {
+        return None;
+    }
+    let configuration = esp_wifi.get_configuration().and_then(|configuration| {
+        // stops retrying, which only starting it again with `configuration` undoes
+        esp_wifi.set_configuration(&wifi::Configuration::None)?;
+        Ok(configuration)
+    });
+    match configuration {
+        Ok(configuration) => {
+            info!("Wifi reconnecting in {:?}", pause);
+            Some((Instant::now() + pause, configuration))
+        }
+        Err(e) => {
+            warn!("could not pause Wifi, it keeps retrying: {:?}", e);
+            None
+        }
+    }
+}
+
+/// Disconnect reasons that won't go away by trying the same network again
+fn is_rejection(reason: u8) -> bool {
+    [
+        sys::wifi_err_reason_t_WIFI_REASON_AUTH_FAIL,
+        sys::wifi_err_reason_t_WIFI_REASON_4WAY_HANDSHAKE_TIMEOUT,
+        sys::wifi_err_reason_t_WIFI_REASON_HANDSHAKE_TIMEOUT,
+        sys::wifi_err_reason_t_WIFI_REASON_NO_AP_FOUND,
+    ]
+    .iter()
+    .any(|&rejection| rejection as u8 == reason)
+}
 
 #[allow(unused)]
 pub struct Wifi {
-    esp_wifi: EspWifi,
+    // dropped first, which ends the event thread
+    events: EventHandlers,
+    watch: WifiWatch,
+    esp_wifi: Arc<Mutex<EspWifi>>,
     netif_stack: Arc<EspNetifStack>,
     sys_loop_stack: Arc<EspSysLoopStack>,
     default_nvs: Arc<EspDefaultNvs>,
 }
 
+impl Wifi {
+    pub fn watch(&self) -> WifiWatch {
+        self.watch.clone()
+    }
+}
+
+/// Connects to the access point `ssid`, reporting progress to the status indicator.
+/// Stays connected for as long as the returned `Wifi` is kept.
+///
+/// Errors carry `FaultCode::Wifi`.
 pub fn wifi(ssid: &str, psk: &str) -> anyhow::Result<Wifi> {
-    let mut auth_method = AuthMethod::WPA2Personal;
-    if ssid.len() == 0 {
-        anyhow::bail!("missing WiFi name")
+    wifi_multi(&[Credentials::new(ssid, psk)])
+}
+
+/// Like `wifi`, with a choice of networks, most preferred first.
+///
+/// Of the networks the scan finds, the most preferred one is tried first,
+/// on the channel of its strongest access point. If that one refuses the
+/// credentials or doesn't connect in time, the next one is tried, and
+/// networks the scan didn't see (e.g. hidden ones) come last. Once
+/// connected, drops are reconnected to the same network, on any of its
+/// access points, after a pause that grows while reconnecting fails.
+pub fn wifi_multi(networks: &[Credentials]) -> anyhow::Result<Wifi> {
+    status::report(Status::WifiConnecting);
+    let wifi = connect(networks, CONNECT_TIMEOUT).context(FaultCode::Wifi);
+    if wifi.is_err() {
+        status::report(Status::Error);
     }
-    if psk.len() == 0 {
-        auth_method = AuthMethod::None;
-        info!("Wifi password is empty");
+    wifi
+}
+
+fn connect(networks: &[Credentials], timeout: Duration) -> anyhow::Result<Wifi> {
+    if networks.is_empty() || networks.iter().any(|network| network.ssid.is_empty()) {
+        anyhow::bail!("missing WiFi name")
     }
     let netif_stack = Arc::new(EspNetifStack::new()?);
     let sys_loop_stack = Arc::new(EspSysLoopStack::new()?);
     let default_nvs = Arc::new(EspDefaultNvs::new()?);
-    let mut wifi = EspWifi::new(
+
+    let (sender, receiver) = mpsc::channel();
+    let events = EventHandlers::register(sender)?;
+    let watch = WifiWatch::new();
+
+    let wifi = Arc::new(Mutex::new(EspWifi::new(
         netif_stack.clone(),
         sys_loop_stack.clone(),
         default_nvs.clone(),
-    )?;
+    )?));
+
+    let thread_watch = watch.clone();
+    let thread_wifi = wifi.clone();
+    thread::Builder::new()
+        .name("wifi-events".into())
+        .stack_size(4096)
+        .spawn(move || run(receiver, thread_watch, thread_wifi))?;
 
-    info!("Searching for Wifi network {}", ssid);
+    info!("Searching for Wifi networks");
 
-    let ap_infos = wifi.scan()?;
+    let ap_infos = wifi.lock().unwrap().scan()?;
 
-    let ours = ap_infos.into_iter().find(|a| a.ssid == ssid);
+    let candidates = candidates(networks, &ap_infos, |ap| {
+        (ap.ssid.as_str(), ap.signal_strength)
+    });
+    for (network, ap) in candidates {
+        match attempt(&wifi, &watch, network, ap, timeout)? {
+            Some(Attempt::Connected) => {
+                info!("Wifi connected!");
+                return Ok(Wifi {
+                    events,
+                    watch,
+                    esp_wifi: wifi,
+                    netif_stack,
+                    sys_loop_stack,
+                    default_nvs,
+                });
+            }
+            Some(Attempt::Rejected) => warn!("{} refused to connect", network.ssid),
+            None => warn!(
+                "no connection to {} within {:?}, status: {:?}",
+                network.ssid,
+                timeout,
+                wifi.lock().unwrap().get_status()
+            ),
+        }
+    }
 
-    let channel = if let Some(ours) = ours {
+    let ssids: Vec<_> = networks
+        .iter()
+        .map(|network| network.ssid.as_str())
+        .collect();
+    bail!("could not connect to any of {:?}", ssids)
+}
+
+fn attempt(
+    wifi: &Mutex<EspWifi>,
+    watch: &WifiWatch,
+    network: &Credentials,
+    ap: Option<&AccessPointInfo>,
+    timeout: Duration,
+) -> anyhow::Result<Option<Attempt>> {
+    let mut auth_method = AuthMethod::WPA2Personal;
+    if network.psk.is_empty() {
+        auth_method = AuthMethod::None;
+        info!("Wifi password for {} is empty", network.ssid);
+    }
+
+    if let Some(ap) = ap {
         info!(
             "Found configured access point {} on channel {}",
-            ssid, ours.channel
+            network.ssid, ap.channel
         );
-        Some(ours.channel)
     } else {
         info!(
             "Configured access point {} not found during scanning, will go with unknown channel",
-            ssid
+            network.ssid
         );
-        None
-    };
+    }
 
     info!("setting Wifi configuration");
-    wifi.set_configuration(&wifi::Configuration::Client(ClientConfiguration {
-        ssid: ssid.into(),
-        password: psk.into(),
-        channel,
+    watch.begin_attempt();
+    let configuration = wifi::Configuration::Client(ClientConfiguration {
+        ssid: network.ssid.clone(),
+        // `EspWifi` reconnects with this configuration, so any access point
+        // of the network will do, not just the one the scan found
+        bssid: None,
+        password: network.psk.clone(),
+        channel: ap.map(|ap| ap.channel),
         auth_method: auth_method,
         ..Default::default()
-    }))?;
-
-    info!("getting Wifi status");
-
-    let status = wifi.get_status();
-
-    if let wifi::Status(
-        ClientStatus::Started(ClientConnectionStatus::Connected(ClientIpStatus::Done(_))),
-        _,
-    ) = status
-    {
-        info!("Wifi connected!");
-    } else {
-        bail!("Unexpected Wifi status: {:?}", status);
-    }
-
-    let wifi = Wifi {
-        esp_wifi: wifi,
-        netif_stack,
-        sys_loop_stack,
-        default_nvs,
-    };
+    });
+    wifi.lock().unwrap().set_configuration(&configuration)?;
+    // events before this still belong to the network tried before
+    post_attempt_started()?;
 
-    Ok(wifi)
+    info!("waiting for Wifi connection");
+    Ok(watch.wait_for_attempt(timeout))
 }
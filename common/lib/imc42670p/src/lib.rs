@@ -42,10 +42,17 @@ where
         self.read_register(Register::WhoAmI)
     }
 
-    /// Starts gyroscope sensor in low noise mode.
+    /// Starts the gyroscope in low noise mode, leaving the accelerometer mode unchanged.
     pub fn gyro_ln(&mut self) -> Result<(), E> {
-        let value: u8 = 0b11 << 2;
-        self. write_pwr_mgmt(value)
+        let bits = self.read_pwr_configuration()?.bits;
+        self.write_pwr_mgmt(bits | 0b11 << 2)
+    }
+
+    /// Starts accelerometer sensor in low noise mode.
+    /// Leaves the gyroscope mode as it is.
+    pub fn accel_ln(&mut self) -> Result<(), E> {
+        let bits = self.read_pwr_configuration()?.bits;
+        self.write_pwr_mgmt(bits | 0b11)
     }
 
     /// Reads gyroscope sensor values.
@@ -70,6 +77,31 @@ where
         
     }
 
+    /// Reads accelerometer sensor values.
+    /// At the default full scale range of ±16 g, 2048 is 1 g.
+    pub fn read_accel(&mut self) -> Result<Data, E> {
+        let x0 = self.read_register(Register::AccelDataX0)?;
+        let x1 = self.read_register(Register::AccelDataX1)?;
+        let y0 = self.read_register(Register::AccelDataY0)?;
+        let y1 = self.read_register(Register::AccelDataY1)?;
+        let z0 = self.read_register(Register::AccelDataZ0)?;
+        let z1 = self.read_register(Register::AccelDataZ1)?;
+
+        Ok(Data {
+            x: i16::from_be_bytes([x1, x0]),
+            y: i16::from_be_bytes([y1, y0]),
+            z: i16::from_be_bytes([z1, z0]),
+        })
+    }
+
+    /// Reads the die temperature.
+    /// Needs the gyroscope or accelerometer to be running, see section 15.5.
+    pub fn read_temperature(&mut self) -> Result<i16, E> {
+        let t0 = self.read_register(Register::TempData0)?;
+        let t1 = self.read_register(Register::TempData1)?;
+        Ok(i16::from_be_bytes([t1, t0]))
+    }
+
     /// Read PwrMgmt0 configuration
     pub fn read_pwr_configuration(&mut self) -> Result<PowerManagement, E> {
         let bits = self.read_register(Register::PwrMgmt0)?;
@@ -82,9 +114,8 @@ where
     }
 
     fn write_register(&mut self, register: Register, value: u8) -> Result<(), E> {
-        let byte = value as u8;
         self.i2c
-            .write(self.address as u8, &[register.address(), byte])
+            .write(self.address as u8, &[register.address(), value])
     }
 
     fn read_register(&mut self, register: Register) -> Result<u8, E> {
@@ -99,15 +130,44 @@ pub struct PowerManagement {
     pub bits: u8,
 }
 
+/// Raw values of a three axis sensor
 pub struct Data {
     pub x: i16,
     pub y: i16,
     pub z: i16,
 }
 
+/// Gyroscope sensitivity at the default full scale range of ±2000 °/s, table 1
+pub const GYRO_LSB_PER_DPS: f32 = 16.4;
+/// Accelerometer sensitivity at the default full scale range of ±16 g, table 2
+pub const ACCEL_LSB_PER_G: f32 = 2048.;
+
+/// Turns a raw gyroscope value into °/s.
+pub fn gyro_dps(raw: i16) -> f32 {
+    raw as f32 / GYRO_LSB_PER_DPS
+}
+
+/// Turns a raw accelerometer value into g.
+pub fn accel_g(raw: i16) -> f32 {
+    raw as f32 / ACCEL_LSB_PER_G
+}
+
+/// Turns a raw die temperature into ℃, see section 15.5.
+pub fn temperature_celsius(raw: i16) -> f32 {
+    raw as f32 / 128. + 25.
+}
+
 // Table 14.1
 #[derive(Clone, Copy)]
 pub enum Register {
+    TempData1 = 0x09,
+    TempData0 = 0x0A,
+    AccelDataX1 = 0x0B,
+    AccelDataX0 = 0x0C,
+    AccelDataY1 = 0x0D,
+    AccelDataY0 = 0x0E,
+    AccelDataZ1 = 0x0F,
+    AccelDataZ0 = 0x10,
     GyroDataX1 = 0x11,
     GyroDataX0 = 0x12,
     GyroDataY1 = 0x13,
@@ -123,3 +183,81 @@ impl Register {
         *self as u8
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gyro_scaling() {
+        assert_eq!(gyro_dps(0), 0.);
+        assert_eq!(gyro_dps(164), 10.);
+        assert_eq!(gyro_dps(-164), -10.);
+        assert!((gyro_dps(i16::MAX) - 1998.0).abs() < 1.);
+    }
+
+    #[test]
+    fn accel_scaling() {
+        assert_eq!(accel_g(0), 0.);
+        assert_eq!(accel_g(2048), 1.);
+        assert_eq!(accel_g(-4096), -2.);
+        assert_eq!(accel_g(i16::MIN), -16.);
+    }
+
+    #[test]
+    fn temperature_scaling() {
+        assert_eq!(temperature_celsius(0), 25.);
+        assert_eq!(temperature_celsius(128), 26.);
+        assert_eq!(temperature_celsius(-64), 24.5);
+        assert_eq!(temperature_celsius(i16::MIN), -231.);
+    }
+
+    /// Only has the `PwrMgmt0` register
+    struct FakeI2c {
+        pwr_mgmt: u8,
+    }
+
+    impl i2c::Write for FakeI2c {
+        type Error = ();
+
+        fn write(&mut self, _address: u8, bytes: &[u8]) -> Result<(), ()> {
+            assert_eq!(bytes[0], Register::PwrMgmt0.address());
+            self.pwr_mgmt = bytes[1];
+            Ok(())
+        }
+    }
+
+    impl i2c::WriteRead for FakeI2c {
+        type Error = ();
+
+        fn write_read(&mut self, _address: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<(), ()> {
+            assert_eq!(bytes, [Register::PwrMgmt0.address()]);
+            buffer[0] = self.pwr_mgmt;
+            Ok(())
+        }
+    }
+
+    fn with_pwr_mgmt(pwr_mgmt: u8) -> IMC42670P<FakeI2c> {
+        IMC42670P::new(FakeI2c { pwr_mgmt }, SlaveAddr::B110_1000).unwrap()
+    }
+
+    #[test]
+    fn gyro_after_reset() {
+        let mut imu = with_pwr_mgmt(0);
+        imu.gyro_ln().unwrap();
+        assert_eq!(imu.i2c.pwr_mgmt, 0b1100);
+    }
+
+    #[test]
+    fn gyro_and_accel() {
+        let mut imu = with_pwr_mgmt(0);
+        imu.accel_ln().unwrap();
+        imu.gyro_ln().unwrap();
+        assert_eq!(imu.i2c.pwr_mgmt, 0b1111);
+
+        // the accelerometer stays in low power mode, bit 7 stays set
+        let mut imu = with_pwr_mgmt(0b1000_0110);
+        imu.gyro_ln().unwrap();
+        assert_eq!(imu.i2c.pwr_mgmt, 0b1000_1110);
+    }
+}
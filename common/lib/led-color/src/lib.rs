@@ -0,0 +1,304 @@
+//! Color pipeline for LED strips: corrections applied to every frame before
+//! it is sent, and conversions from other color models to RGB.
+//!
+//! Pure math without any hardware access, so it can be tested on the host.
+
+use rgb::ComponentMap;
+pub use rgb::RGB8;
+
+/// Gamma 2.8, so that brightness steps look even to the eye
+#[rustfmt::skip]
+const GAMMA8: [u8; 256] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1,
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2,
+    2, 3, 3, 3, 3, 3, 3, 3, 4, 4, 4, 4, 4, 5, 5, 5,
+    5, 6, 6, 6, 6, 7, 7, 7, 7, 8, 8, 8, 9, 9, 9, 10,
+    10, 10, 11, 11, 11, 12, 12, 13, 13, 13, 14, 14, 15, 15, 16, 16,
+    17, 17, 18, 18, 19, 19, 20, 20, 21, 21, 22, 22, 23, 24, 24, 25,
+    25, 26, 27, 27, 28, 29, 29, 30, 31, 32, 32, 33, 34, 35, 35, 36,
+    37, 38, 39, 39, 40, 41, 42, 43, 44, 45, 46, 47, 48, 49, 50, 50,
+    51, 52, 54, 55, 56, 57, 58, 59, 60, 61, 62, 63, 64, 66, 67, 68,
+    69, 70, 72, 73, 74, 75, 77, 78, 79, 81, 82, 83, 85, 86, 87, 89,
+    90, 92, 93, 95, 96, 98, 99, 101, 102, 104, 105, 107, 109, 110, 112, 114,
+    115, 117, 119, 120, 122, 124, 126, 127, 129, 131, 133, 135, 137, 138, 140, 142,
+    144, 146, 148, 150, 152, 154, 156, 158, 160, 162, 164, 167, 169, 171, 173, 175,
+    177, 180, 182, 184, 186, 189, 191, 193, 196, 198, 200, 203, 205, 208, 210, 213,
+    215, 218, 220, 223, 225, 228, 231, 233, 236, 239, 241, 244, 247, 249, 252, 255,
+];
+
+/// Typical current of one fully lit WS2812 color channel
+const MA_PER_CHANNEL: u32 = 20;
+
+/// Corrections `WS2812RMT` applies to the frame buffer before sending it.
+///
+/// The frame buffer keeps the colors as set, so changing the correction
+/// doesn't lose information. The default changes nothing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ColorCorrection {
+    pub gamma: bool,
+    /// Scales all channels, 255 is full brightness
+    pub brightness: u8,
+    /// Dims the whole frame if its estimated current draw is above this
+    pub max_current_ma: Option<u32>,
+}
+
+impl Default for ColorCorrection {
+    fn default() -> Self {
+        Self {
+            gamma: false,
+            brightness: 255,
+            max_current_ma: None,
+        }
+    }
+}
+
+impl ColorCorrection {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn gamma(mut self, gamma: bool) -> Self {
+        self.gamma = gamma;
+        self
+    }
+
+    pub fn brightness(mut self, brightness: u8) -> Self {
+        self.brightness = brightness;
+        self
+    }
+
+    pub fn max_current_ma(mut self, max_current_ma: u32) -> Self {
+        self.max_current_ma = Some(max_current_ma);
+        self
+    }
+
+    /// Writes the corrected `pixels` to `out`, which must be as long.
+    pub fn apply(&self, pixels: &[RGB8], out: &mut [RGB8]) {
+        for (pixel, out) in pixels.iter().zip(out.iter_mut()) {
+            *out = pixel.map(|c| {
+                let c = if self.gamma { GAMMA8[c as usize] } else { c };
+                scale(c, self.brightness as u32, 255)
+            });
+        }
+
+        if let Some(max_current_ma) = self.max_current_ma {
+            let current_ma = estimated_current_ma(out);
+            if current_ma > max_current_ma {
+                for pixel in out.iter_mut() {
+                    *pixel = pixel.map(|c| scale(c, max_current_ma, current_ma));
+                }
+            }
+        }
+    }
+}
+
+/// What a frame draws from the supply, not counting the LEDs' own idle current.
+pub fn estimated_current_ma(pixels: &[RGB8]) -> u32 {
+    let channel_sum: u32 = pixels
+        .iter()
+        .map(|p| p.r as u32 + p.g as u32 + p.b as u32)
+        .sum();
+    channel_sum * MA_PER_CHANNEL / 255
+}
+
+/// `c * numerator / denominator`, rounded down, so that the result never exceeds the limit.
+fn scale(c: u8, numerator: u32, denominator: u32) -> u8 {
+    (c as u32 * numerator / denominator) as u8
+}
+
+/// The color `progress` of the way from `from` to `to`, with `progress` from 0.0 to 1.0
+pub fn lerp(from: RGB8, to: RGB8, progress: f32) -> RGB8 {
+    let channel =
+        |from: u8, to: u8| (from as f32 + (to as f32 - from as f32) * progress).round() as u8;
+    RGB8::new(
+        channel(from.r, to.r),
+        channel(from.g, to.g),
+        channel(from.b, to.b),
+    )
+}
+
+/// Hue in degrees, saturation and value from 0.0 to 1.0
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Hsv {
+    pub h: f32,
+    pub s: f32,
+    pub v: f32,
+}
+
+impl Hsv {
+    pub fn new(h: f32, s: f32, v: f32) -> Self {
+        Self { h, s, v }
+    }
+}
+
+impl From<Hsv> for RGB8 {
+    fn from(hsv: Hsv) -> Self {
+        let v = hsv.v.clamp(0., 1.);
+        let chroma = v * hsv.s.clamp(0., 1.);
+        from_hue(hsv.h, chroma, v - chroma)
+    }
+}
+
+/// Hue in degrees, saturation and lightness from 0.0 to 1.0
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Hsl {
+    pub h: f32,
+    pub s: f32,
+    pub l: f32,
+}
+
+impl Hsl {
+    pub fn new(h: f32, s: f32, l: f32) -> Self {
+        Self { h, s, l }
+    }
+}
+
+impl From<Hsl> for RGB8 {
+    fn from(hsl: Hsl) -> Self {
+        let l = hsl.l.clamp(0., 1.);
+        let chroma = (1. - (2. * l - 1.).abs()) * hsl.s.clamp(0., 1.);
+        from_hue(hsl.h, chroma, l - chroma / 2.)
+    }
+}
+
+/// The RGB color with `hue`, `chroma` and `min` as the smallest channel.
+fn from_hue(hue: f32, chroma: f32, min: f32) -> RGB8 {
+    let sector = hue.rem_euclid(360.) / 60.;
+    let x = chroma * (1. - (sector % 2. - 1.).abs());
+    let (r, g, b) = match sector as u8 {
+        0 => (chroma, x, 0.),
+        1 => (x, chroma, 0.),
+        2 => (0., chroma, x),
+        3 => (0., x, chroma),
+        4 => (x, 0., chroma),
+        _ => (chroma, 0., x),
+    };
+    let channel = |c: f32| ((c + min) * 255.).round() as u8;
+    RGB8::new(channel(r), channel(g), channel(b))
+}
+
+/// The color of a black body at `kelvin`, from 1000K (candle) to 40000K (blue sky).
+///
+/// Uses Tanner Helland's approximation of the black body spectrum.
+pub fn kelvin(kelvin: u32) -> RGB8 {
+    let t = kelvin.clamp(1000, 40000) as f32 / 100.;
+    let channel = |c: f32| c.clamp(0., 255.).round() as u8;
+
+    let r = if t <= 66. {
+        255.
+    } else {
+        329.698_73 * (t - 60.).powf(-0.133_204_76)
+    };
+    let g = if t <= 66. {
+        99.470_8 * t.ln() - 161.119_57
+    } else {
+        288.122_16 * (t - 60.).powf(-0.075_514_846)
+    };
+    let b = if t >= 66. {
+        255.
+    } else if t <= 19. {
+        0.
+    } else {
+        138.517_73 * (t - 10.).ln() - 305.044_8
+    };
+    RGB8::new(channel(r), channel(g), channel(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn corrected(correction: ColorCorrection, pixels: &[RGB8]) -> Vec<RGB8> {
+        let mut out = vec![RGB8::default(); pixels.len()];
+        correction.apply(pixels, &mut out);
+        out
+    }
+
+    #[test]
+    fn default_changes_nothing() {
+        let pixels = [RGB8::new(0, 128, 255), RGB8::new(1, 2, 3)];
+        assert_eq!(corrected(ColorCorrection::new(), &pixels), pixels);
+    }
+
+    #[test]
+    fn gamma() {
+        let pixels = [RGB8::new(0, 128, 255)];
+        let out = corrected(ColorCorrection::new().gamma(true), &pixels);
+        assert_eq!(out, [RGB8::new(0, 37, 255)]);
+    }
+
+    #[test]
+    fn brightness() {
+        let pixels = [RGB8::new(255, 100, 1)];
+        let out = corrected(ColorCorrection::new().brightness(128), &pixels);
+        assert_eq!(out, [RGB8::new(128, 50, 0)]);
+        let out = corrected(ColorCorrection::new().brightness(0), &pixels);
+        assert_eq!(out, [RGB8::default()]);
+    }
+
+    #[test]
+    fn current_limit() {
+        // 3 white pixels draw 180mA
+        let pixels = [RGB8::new(255, 255, 255); 3];
+        assert_eq!(estimated_current_ma(&pixels), 180);
+
+        let out = corrected(ColorCorrection::new().max_current_ma(60), &pixels);
+        assert!(estimated_current_ma(&out) <= 60);
+        assert_eq!(out[0], RGB8::new(85, 85, 85));
+
+        // below the limit nothing is dimmed
+        let out = corrected(ColorCorrection::new().max_current_ma(200), &pixels);
+        assert_eq!(out, pixels);
+    }
+
+    #[test]
+    fn interpolation() {
+        let from = RGB8::new(0, 100, 255);
+        let to = RGB8::new(255, 0, 255);
+        assert_eq!(lerp(from, to, 0.), from);
+        assert_eq!(lerp(from, to, 1.), to);
+        assert_eq!(lerp(from, to, 0.5), RGB8::new(128, 50, 255));
+    }
+
+    #[test]
+    fn hsv() {
+        assert_eq!(RGB8::from(Hsv::new(0., 1., 1.)), RGB8::new(255, 0, 0));
+        assert_eq!(RGB8::from(Hsv::new(120., 1., 1.)), RGB8::new(0, 255, 0));
+        assert_eq!(RGB8::from(Hsv::new(240., 1., 1.)), RGB8::new(0, 0, 255));
+        assert_eq!(RGB8::from(Hsv::new(60., 1., 1.)), RGB8::new(255, 255, 0));
+        assert_eq!(RGB8::from(Hsv::new(0., 0., 1.)), RGB8::new(255, 255, 255));
+        assert_eq!(RGB8::from(Hsv::new(0., 1., 0.)), RGB8::default());
+    }
+
+    #[test]
+    fn hue_wraps_around() {
+        assert_eq!(RGB8::from(Hsv::new(360., 1., 1.)), RGB8::new(255, 0, 0));
+        assert_eq!(RGB8::from(Hsv::new(-120., 1., 1.)), RGB8::new(0, 0, 255));
+    }
+
+    #[test]
+    fn out_of_range_is_clamped() {
+        assert_eq!(RGB8::from(Hsv::new(0., 2., 2.)), RGB8::new(255, 0, 0));
+        assert_eq!(RGB8::from(Hsl::new(0., -1., 0.5)), RGB8::new(128, 128, 128));
+    }
+
+    #[test]
+    fn hsl() {
+        assert_eq!(RGB8::from(Hsl::new(0., 1., 0.5)), RGB8::new(255, 0, 0));
+        assert_eq!(RGB8::from(Hsl::new(180., 1., 0.5)), RGB8::new(0, 255, 255));
+        assert_eq!(RGB8::from(Hsl::new(0., 1., 1.)), RGB8::new(255, 255, 255));
+        assert_eq!(RGB8::from(Hsl::new(0., 1., 0.)), RGB8::default());
+    }
+
+    #[test]
+    fn kelvins() {
+        assert_eq!(kelvin(6600), RGB8::new(255, 255, 255));
+        let candle = kelvin(1000);
+        assert_eq!((candle.r, candle.b), (255, 0));
+        assert!(candle.g < 100);
+        let sky = kelvin(40000);
+        assert!(sky.b == 255 && sky.r < sky.b);
+        assert_eq!(kelvin(500), kelvin(1000));
+    }
+}
@@ -0,0 +1,257 @@
+//! Hardware-independent parts of the board support crate's LED effects.
+//!
+//! The board support crate plays the effects on a thread of its own; what a
+//! frame looks like at a given time since the effect started lives here, so
+//! it can be tested on the host.
+
+use std::f32::consts::PI;
+use std::time::Duration;
+
+use led_color::{lerp, Hsv};
+use rgb::RGB8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Effect {
+    Off,
+    Solid(RGB8),
+    /// Switches between `color` and off.
+    Blink {
+        color: RGB8,
+        on: Duration,
+        off: Duration,
+    },
+    /// Fades `color` in and out once per `period`.
+    Breathe {
+        color: RGB8,
+        period: Duration,
+    },
+    /// Cycles through all hues once per `period`, spread over the strip.
+    Rainbow {
+        period: Duration,
+    },
+    /// Moves a single lit pixel along the strip, one pixel per `step`.
+    Chase {
+        color: RGB8,
+        step: Duration,
+    },
+    /// Fades from whatever is shown now to `color`, then stays there.
+    FadeTo {
+        color: RGB8,
+        duration: Duration,
+    },
+    /// Blinks `color` `code` times, pauses, and starts over, so the code can be counted.
+    BlinkCode {
+        color: RGB8,
+        code: u8,
+    },
+}
+
+pub const CODE_BLINK: Duration = Duration::from_millis(200);
+pub const CODE_PAUSE: Duration = Duration::from_secs(1);
+
+/// How long one round of `Effect::BlinkCode` with `code` takes
+pub fn blink_code_period(code: u8) -> Duration {
+    CODE_BLINK * 2 * code as u32 + CODE_PAUSE
+}
+
+/// Draws `effect` as it looks `elapsed` after it started. `from` is the frame shown at its start.
+pub fn render(effect: &Effect, elapsed: Duration, from: &[RGB8], frame: &mut [RGB8]) {
+    let pixel_count = frame.len();
+    match *effect {
+        Effect::Off => frame.fill(RGB8::default()),
+        Effect::Solid(color) => frame.fill(color),
+        Effect::Blink { color, on, off } => {
+            let lit = phase(elapsed, on + off) < on.as_secs_f32() / (on + off).as_secs_f32();
+            frame.fill(if lit { color } else { RGB8::default() });
+        }
+        Effect::Breathe { color, period } => {
+            let level = (1. - (2. * PI * phase(elapsed, period)).cos()) / 2.;
+            frame.fill(scale(color, level));
+        }
+        Effect::Rainbow { period } => {
+            let phase = phase(elapsed, period);
+            for (i, pixel) in frame.iter_mut().enumerate() {
+                let hue = 360. * (phase + i as f32 / pixel_count as f32);
+                *pixel = Hsv::new(hue, 1., 1.).into();
+            }
+        }
+        Effect::Chase { color, step } => {
+            let lit = (elapsed.as_millis() / step.as_millis().max(1)) as usize % pixel_count;
+            for (i, pixel) in frame.iter_mut().enumerate() {
+                *pixel = if i == lit { color } else { RGB8::default() };
+            }
+        }
+        Effect::FadeTo { color, duration } => {
+            let progress = if duration.is_zero() {
+                1.
+            } else {
+                (elapsed.as_secs_f32() / duration.as_secs_f32()).min(1.)
+            };
+            for (pixel, from) in frame.iter_mut().zip(from) {
+                *pixel = lerp(*from, color, progress);
+            }
+        }
+        Effect::BlinkCode { color, code } => {
+            let into = elapsed.as_millis() % blink_code_period(code).as_millis();
+            // on for one `CODE_BLINK`, off for the next, `code` times
+            let blinking = into < 2 * code as u128 * CODE_BLINK.as_millis();
+            let lit = blinking && into % (2 * CODE_BLINK.as_millis()) < CODE_BLINK.as_millis();
+            frame.fill(if lit { color } else { RGB8::default() });
+        }
+    }
+}
+
+/// Where in its `period` an effect is, from 0.0 to 1.0
+fn phase(elapsed: Duration, period: Duration) -> f32 {
+    if period.is_zero() {
+        return 0.;
+    }
+    (elapsed.as_secs_f32() % period.as_secs_f32()) / period.as_secs_f32()
+}
+
+fn scale(color: RGB8, level: f32) -> RGB8 {
+    lerp(RGB8::default(), color, level)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: RGB8 = RGB8 { r: 100, g: 0, b: 0 };
+    const OFF: RGB8 = RGB8 { r: 0, g: 0, b: 0 };
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    fn frame(effect: Effect, elapsed: Duration, pixels: usize) -> Vec<RGB8> {
+        let from = vec![RGB8::new(0, 0, 200); pixels];
+        let mut frame = vec![OFF; pixels];
+        render(&effect, elapsed, &from, &mut frame);
+        frame
+    }
+
+    fn pixel(effect: Effect, elapsed: Duration) -> RGB8 {
+        frame(effect, elapsed, 1)[0]
+    }
+
+    #[test]
+    fn blink_edges() {
+        let blink = Effect::Blink {
+            color: RED,
+            on: ms(300),
+            off: ms(100),
+        };
+        assert_eq!(pixel(blink, ms(0)), RED);
+        assert_eq!(pixel(blink, ms(299)), RED);
+        assert_eq!(pixel(blink, ms(300)), OFF);
+        assert_eq!(pixel(blink, ms(399)), OFF);
+        assert_eq!(pixel(blink, ms(400)), RED);
+        assert_eq!(pixel(blink, ms(4_350)), OFF);
+    }
+
+    #[test]
+    fn breathe_wraps_at_period() {
+        let breathe = Effect::Breathe {
+            color: RED,
+            period: ms(2000),
+        };
+        assert_eq!(pixel(breathe, ms(0)), OFF);
+        assert_eq!(pixel(breathe, ms(1000)), RED);
+        assert_eq!(pixel(breathe, ms(2000)), OFF);
+        assert_eq!(pixel(breathe, ms(3000)), RED);
+        // rising and falling the same way
+        assert_eq!(pixel(breathe, ms(500)), pixel(breathe, ms(1500)));
+        assert!(pixel(breathe, ms(500)).r < RED.r);
+    }
+
+    #[test]
+    fn rainbow_wraps_at_period() {
+        let rainbow = Effect::Rainbow { period: ms(1000) };
+        assert_eq!(frame(rainbow, ms(0), 3), frame(rainbow, ms(1000), 3));
+        assert_eq!(frame(rainbow, ms(250), 3), frame(rainbow, ms(2250), 3));
+        assert_ne!(frame(rainbow, ms(0), 3), frame(rainbow, ms(500), 3));
+        // spread over the strip: every pixel has a hue of its own
+        let strip = frame(rainbow, ms(0), 3);
+        assert_ne!(strip[0], strip[1]);
+        assert_ne!(strip[1], strip[2]);
+        assert_eq!(strip[0], Hsv::new(0., 1., 1.).into());
+    }
+
+    #[test]
+    fn chase_position() {
+        let chase = Effect::Chase {
+            color: RED,
+            step: ms(100),
+        };
+        assert_eq!(frame(chase, ms(0), 3), [RED, OFF, OFF]);
+        assert_eq!(frame(chase, ms(199), 3), [OFF, RED, OFF]);
+        assert_eq!(frame(chase, ms(200), 3), [OFF, OFF, RED]);
+        assert_eq!(frame(chase, ms(300), 3), [RED, OFF, OFF]);
+        // a single LED stays lit
+        assert_eq!(frame(chase, ms(150), 1), [RED]);
+    }
+
+    #[test]
+    fn fade_to_start_and_end() {
+        let fade = Effect::FadeTo {
+            color: RED,
+            duration: ms(1000),
+        };
+        assert_eq!(pixel(fade, ms(0)), RGB8::new(0, 0, 200));
+        assert_eq!(pixel(fade, ms(500)), RGB8::new(50, 0, 100));
+        assert_eq!(pixel(fade, ms(1000)), RED);
+        assert_eq!(pixel(fade, ms(5000)), RED);
+
+        let instant = Effect::FadeTo {
+            color: RED,
+            duration: Duration::ZERO,
+        };
+        assert_eq!(pixel(instant, ms(0)), RED);
+    }
+
+    #[test]
+    fn blink_code() {
+        let code = Effect::BlinkCode {
+            color: RED,
+            code: 2,
+        };
+        let lit: Vec<bool> = (0..8).map(|i| pixel(code, CODE_BLINK * i) == RED).collect();
+        // two blinks, then the pause
+        assert_eq!(lit, [true, false, true, false, false, false, false, false]);
+        assert_eq!(pixel(code, blink_code_period(2)), RED);
+        assert_eq!(pixel(code, blink_code_period(2) - ms(1)), OFF);
+    }
+
+    #[test]
+    fn blink_code_period_counts_blinks() {
+        assert_eq!(blink_code_period(0), CODE_PAUSE);
+        assert_eq!(blink_code_period(1), ms(1400));
+        assert_eq!(blink_code_period(5), ms(3000));
+    }
+
+    #[test]
+    fn zero_periods() {
+        assert_eq!(
+            pixel(
+                Effect::Breathe {
+                    color: RED,
+                    period: Duration::ZERO
+                },
+                ms(10)
+            ),
+            OFF
+        );
+        assert_eq!(
+            frame(
+                Effect::Chase {
+                    color: RED,
+                    step: Duration::ZERO
+                },
+                ms(1),
+                3
+            ),
+            [OFF, RED, OFF]
+        );
+    }
+}
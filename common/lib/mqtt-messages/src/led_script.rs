@@ -0,0 +1,342 @@
+//! LED animation scripts
+//!
+//! A script is a list of keyframes. Each keyframe fades from the color of the
+//! previous keyframe to its own color, the first one fades from the color of
+//! the last one, so repeating scripts loop seamlessly.
+
+use std::str::FromStr;
+use std::time::Duration;
+
+use led_color::lerp;
+
+use crate::{ConvertError, RGB8};
+
+/// How a keyframe moves from the previous color to its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Easing {
+    /// Jumps to the keyframe's color right away and holds it.
+    Step,
+    Linear,
+    EaseIn,
+    EaseOut,
+    EaseInOut,
+}
+
+impl Easing {
+    /// Maps linear progress in `0.0..=1.0` to eased progress.
+    pub fn apply(&self, t: f32) -> f32 {
+        let t = t.clamp(0., 1.);
+        match self {
+            Easing::Step => 1.,
+            Easing::Linear => t,
+            Easing::EaseIn => t * t,
+            Easing::EaseOut => t * (2. - t),
+            Easing::EaseInOut => {
+                if t < 0.5 {
+                    2. * t * t
+                } else {
+                    -1. + (4. - 2. * t) * t
+                }
+            }
+        }
+    }
+
+    fn to_byte(self) -> u8 {
+        match self {
+            Easing::Step => 0,
+            Easing::Linear => 1,
+            Easing::EaseIn => 2,
+            Easing::EaseOut => 3,
+            Easing::EaseInOut => 4,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Easing::Step),
+            1 => Some(Easing::Linear),
+            2 => Some(Easing::EaseIn),
+            3 => Some(Easing::EaseOut),
+            4 => Some(Easing::EaseInOut),
+            _ => None,
+        }
+    }
+}
+
+/// Parses the names used in script files, e.g. `ease-in-out`.
+impl FromStr for Easing {
+    type Err = ConvertError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "step" => Ok(Easing::Step),
+            "linear" => Ok(Easing::Linear),
+            "ease-in" => Ok(Easing::EaseIn),
+            "ease-out" => Ok(Easing::EaseOut),
+            "ease-in-out" => Ok(Easing::EaseInOut),
+            _ => Err(ConvertError::InvalidData),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Keyframe {
+    pub color: RGB8,
+    pub duration_ms: u16,
+    pub easing: Easing,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LedScript {
+    pub keyframes: Vec<Keyframe>,
+    /// Start over once the last keyframe is reached.
+    pub repeat: bool,
+}
+
+impl LedScript {
+    const KEYFRAME_SIZE: usize = 6;
+    const FLAG_REPEAT: u8 = 0b1;
+
+    /// The most keyframes that fit into the board's default MQTT buffer of 1 KiB,
+    /// together with the topic.
+    pub const MAX_KEYFRAMES: usize = 150;
+
+    pub fn duration(&self) -> Duration {
+        Duration::from_millis(self.total_ms())
+    }
+
+    /// Whether a script that doesn't repeat has been played completely.
+    pub fn finished(&self, elapsed: Duration) -> bool {
+        !self.repeat && elapsed >= self.duration()
+    }
+
+    /// The color to show `elapsed` after the script started.
+    ///
+    /// Once a script that doesn't repeat has finished, this is the color of
+    /// its last keyframe.
+    pub fn color_at(&self, elapsed: Duration) -> RGB8 {
+        let total_ms = self.total_ms();
+        let last = match self.keyframes.last() {
+            Some(last) => last,
+            None => return RGB8::default(),
+        };
+        if total_ms == 0 || self.finished(elapsed) {
+            return last.color;
+        }
+
+        let mut elapsed_ms = elapsed.as_millis() as u64 % total_ms;
+        let mut from = last.color;
+        for keyframe in &self.keyframes {
+            let duration_ms = keyframe.duration_ms as u64;
+            if elapsed_ms < duration_ms {
+                let progress = keyframe
+                    .easing
+                    .apply(elapsed_ms as f32 / duration_ms as f32);
+                return lerp(from, keyframe.color, progress);
+            }
+            elapsed_ms -= duration_ms;
+            from = keyframe.color;
+        }
+        last.color
+    }
+
+    /// Binary representation as sent with `Command::BoardLedScript`:
+    /// one flag byte, followed by `r, g, b, duration_ms (u16, BE), easing` per keyframe.
+    pub fn encode(&self) -> Vec<u8> {
+        let flags = if self.repeat { Self::FLAG_REPEAT } else { 0 };
+        let mut data = Vec::with_capacity(1 + self.keyframes.len() * Self::KEYFRAME_SIZE);
+        data.push(flags);
+        for keyframe in &self.keyframes {
+            let duration = keyframe.duration_ms.to_be_bytes();
+            data.extend_from_slice(&[
+                keyframe.color.r,
+                keyframe.color.g,
+                keyframe.color.b,
+                duration[0],
+                duration[1],
+                keyframe.easing.to_byte(),
+            ]);
+        }
+        data
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, ConvertError> {
+        let (flags, keyframes) = data.split_first().ok_or(ConvertError::Length(data.len()))?;
+        if keyframes.len() % Self::KEYFRAME_SIZE != 0 {
+            return Err(ConvertError::Length(data.len()));
+        }
+
+        let keyframes = keyframes
+            .chunks(Self::KEYFRAME_SIZE)
+            .map(|chunk| {
+                Ok(Keyframe {
+                    color: RGB8::new(chunk[0], chunk[1], chunk[2]),
+                    duration_ms: u16::from_be_bytes([chunk[3], chunk[4]]),
+                    easing: Easing::from_byte(chunk[5]).ok_or(ConvertError::InvalidData)?,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(LedScript {
+            keyframes,
+            repeat: flags & Self::FLAG_REPEAT != 0,
+        })
+    }
+
+    fn total_ms(&self) -> u64 {
+        self.keyframes.iter().map(|k| k.duration_ms as u64).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keyframe(color: RGB8, duration_ms: u16, easing: Easing) -> Keyframe {
+        Keyframe {
+            color,
+            duration_ms,
+            easing,
+        }
+    }
+
+    const RED: RGB8 = RGB8 { r: 200, g: 0, b: 0 };
+    const BLUE: RGB8 = RGB8 { r: 0, g: 0, b: 200 };
+
+    /// Fades to red in 1s, then to blue in 1s
+    fn red_blue(easing: Easing, repeat: bool) -> LedScript {
+        LedScript {
+            keyframes: vec![keyframe(RED, 1000, easing), keyframe(BLUE, 1000, easing)],
+            repeat,
+        }
+    }
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn round_trip() {
+        let script = LedScript {
+            keyframes: vec![
+                keyframe(RGB8::new(1, 2, 3), 0, Easing::Step),
+                keyframe(RGB8::new(4, 5, 6), 258, Easing::Linear),
+                keyframe(RGB8::new(7, 8, 9), u16::MAX, Easing::EaseIn),
+                keyframe(RGB8::new(10, 11, 12), 1, Easing::EaseOut),
+                keyframe(RGB8::new(13, 14, 15), 500, Easing::EaseInOut),
+            ],
+            repeat: true,
+        };
+        let data = script.encode();
+        assert_eq!(data.len(), 1 + 5 * 6);
+        assert_eq!(data[..7], [1, 1, 2, 3, 0, 0, 0]);
+        assert_eq!(data[7..13], [4, 5, 6, 1, 2, 1]);
+        assert_eq!(LedScript::decode(&data).unwrap(), script);
+
+        let empty = LedScript::default();
+        assert_eq!(empty.encode(), [0]);
+        assert_eq!(LedScript::decode(&[0]).unwrap(), empty);
+    }
+
+    #[test]
+    fn invalid_data() {
+        assert!(matches!(
+            LedScript::decode(&[]),
+            Err(ConvertError::Length(0))
+        ));
+        // one byte short of a keyframe
+        let mut data = red_blue(Easing::Linear, false).encode();
+        data.pop();
+        assert!(matches!(
+            LedScript::decode(&data),
+            Err(ConvertError::Length(12))
+        ));
+        // unknown easing
+        assert!(matches!(
+            LedScript::decode(&[0, 1, 2, 3, 0, 10, 5]),
+            Err(ConvertError::InvalidData)
+        ));
+    }
+
+    #[test]
+    fn unknown_flags_are_ignored() {
+        let script = LedScript::decode(&[0b10, 1, 2, 3, 0, 10, 1]).unwrap();
+        assert!(!script.repeat);
+        assert_eq!(script.keyframes.len(), 1);
+    }
+
+    #[test]
+    fn easing_boundaries() {
+        for easing in [
+            Easing::Linear,
+            Easing::EaseIn,
+            Easing::EaseOut,
+            Easing::EaseInOut,
+        ] {
+            assert_eq!(easing.apply(0.), 0.);
+            assert_eq!(easing.apply(1.), 1.);
+            // clamped outside of 0.0..=1.0
+            assert_eq!(easing.apply(-1.), 0.);
+            assert_eq!(easing.apply(2.), 1.);
+        }
+        assert_eq!(Easing::Step.apply(0.), 1.);
+        assert_eq!(Easing::EaseIn.apply(0.5), 0.25);
+        assert_eq!(Easing::EaseOut.apply(0.5), 0.75);
+        assert_eq!(Easing::EaseInOut.apply(0.5), 0.5);
+        assert_eq!(Easing::EaseInOut.apply(0.25), 0.125);
+    }
+
+    #[test]
+    fn interpolates_between_keyframes() {
+        let script = red_blue(Easing::Linear, false);
+        // the first keyframe fades from the last one
+        assert_eq!(script.color_at(ms(0)), BLUE);
+        assert_eq!(script.color_at(ms(500)), RGB8::new(100, 0, 100));
+        assert_eq!(script.color_at(ms(1000)), RED);
+        assert_eq!(script.color_at(ms(1250)), RGB8::new(150, 0, 50));
+    }
+
+    #[test]
+    fn eased_interpolation() {
+        let script = red_blue(Easing::EaseIn, false);
+        assert_eq!(script.color_at(ms(500)), RGB8::new(50, 0, 150));
+        let script = red_blue(Easing::Step, false);
+        assert_eq!(script.color_at(ms(1)), RED);
+        assert_eq!(script.color_at(ms(1001)), BLUE);
+    }
+
+    #[test]
+    fn ends_on_last_keyframe() {
+        let script = red_blue(Easing::Linear, false);
+        assert_eq!(script.duration(), ms(2000));
+        assert!(!script.finished(ms(1999)));
+        assert!(script.finished(ms(2000)));
+        assert_eq!(script.color_at(ms(2000)), BLUE);
+        assert_eq!(script.color_at(ms(60_000)), BLUE);
+    }
+
+    #[test]
+    fn repeats() {
+        let script = red_blue(Easing::Linear, true);
+        assert!(!script.finished(ms(60_000)));
+        assert_eq!(script.color_at(ms(2500)), script.color_at(ms(500)));
+        assert_eq!(script.color_at(ms(4000)), BLUE);
+    }
+
+    #[test]
+    fn degenerate_scripts() {
+        assert_eq!(LedScript::default().color_at(ms(100)), RGB8::default());
+        let instant = LedScript {
+            keyframes: vec![keyframe(RED, 0, Easing::Linear)],
+            repeat: true,
+        };
+        assert_eq!(instant.color_at(ms(100)), RED);
+    }
+
+    #[test]
+    fn easing_names() {
+        assert_eq!("ease-in-out".parse::<Easing>().unwrap(), Easing::EaseInOut);
+        assert_eq!("step".parse::<Easing>().unwrap(), Easing::Step);
+        assert!("bounce".parse::<Easing>().is_err());
+    }
+}
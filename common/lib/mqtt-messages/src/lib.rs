@@ -1,12 +1,13 @@
 use std::borrow::{Borrow, Cow};
 
-use rgb::ComponentSlice;
+pub use led_script::{Easing, Keyframe, LedScript};
 pub use rgb::RGB8;
 
+mod led_script;
+
 /// Handles `EspMqttMessage` with MQTT hierarchy
 ///
 /// Can be used to send ColorData(rgb) with `Command`
-
 pub fn cmd_topic_fragment(uuid: &str) -> String {
     format!("{}/command/", uuid)
 }
@@ -26,28 +27,79 @@ pub fn hello_topic(uuid: &str) -> String {
     format!("{}/hello", uuid)
 }
 
+/// A sensor value published by the board
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SensorReading {
+    /// Chip temperature in °C, sent on `temperature_data_topic`
+    Temperature(f32),
+}
+
+impl SensorReading {
+    pub fn topic(&self, uuid: &str) -> String {
+        match self {
+            SensorReading::Temperature(_) => temperature_data_topic(uuid),
+        }
+    }
+
+    /// Name of the sensor, e.g. for configuration files
+    pub fn name(&self) -> &'static str {
+        match self {
+            SensorReading::Temperature(_) => "temperature",
+        }
+    }
+
+    pub fn value(&self) -> f32 {
+        match self {
+            SensorReading::Temperature(value) => *value,
+        }
+    }
+
+    /// The value as a big endian `f32`
+    pub fn data(&self) -> [u8; 4] {
+        self.value().to_be_bytes()
+    }
+
+    /// Decodes a reading that board `uuid` published on `topic`
+    pub fn decode(uuid: &str, topic: &str, data: &[u8]) -> Result<Self, ConvertError> {
+        if topic == temperature_data_topic(uuid) {
+            let data: [u8; 4] = data
+                .try_into()
+                .map_err(|_| ConvertError::Length(data.len()))?;
+            Ok(SensorReading::Temperature(f32::from_be_bytes(data)))
+        } else {
+            Err(ConvertError::InvalidPath)
+        }
+    }
+}
+
 pub enum Command {
     BoardLed(RGB8),
+    /// Played back by the board on its own, see `LedScript`
+    BoardLedScript(LedScript),
 }
 
 impl Command {
     const BOARD_LED: &'static str = "board_led";
+    const BOARD_LED_SCRIPT: &'static str = "board_led_script";
 
     pub fn topic(&self, uuid: &str) -> String {
         match self {
             Command::BoardLed(_) => format!("{}{}", cmd_topic_fragment(uuid), Self::BOARD_LED),
+            Command::BoardLedScript(_) => {
+                format!("{}{}", cmd_topic_fragment(uuid), Self::BOARD_LED_SCRIPT)
+            }
         }
     }
 
-    pub fn data(&self) -> &[u8] {
+    pub fn data(&self) -> Cow<'_, [u8]> {
         match self {
-            Command::BoardLed(led_data) => led_data.as_slice(),
+            Command::BoardLed(led_data) => Cow::Borrowed(led_data.as_ref()),
+            Command::BoardLedScript(script) => Cow::Owned(script.encode()),
         }
     }
 }
 
 /// `ColorData` is a simplified `Command`
-
 pub enum ColorData {
     BoardLed(RGB8),
 }
@@ -59,7 +111,7 @@ impl ColorData {
     }
     pub fn data(&self) -> &[u8] {
         match self {
-            ColorData::BoardLed(led_data) => led_data.as_slice(),
+            ColorData::BoardLed(led_data) => led_data.as_ref(),
         }
     }
 }
@@ -78,13 +130,19 @@ impl<'a> TryFrom<Command> for RawCommandData<'a> {
                 data: Cow::Owned(vec![rgb.r, rgb.g, rgb.b]),
                 path: Command::BOARD_LED,
             }),
+            Command::BoardLedScript(script) => Ok(RawCommandData {
+                data: Cow::Owned(script.encode()),
+                path: Command::BOARD_LED_SCRIPT,
+            }),
         }
     }
 }
 
+#[derive(Debug)]
 pub enum ConvertError {
     Length(usize),
     InvalidPath,
+    InvalidData,
 }
 
 impl<'a> TryFrom<RawCommandData<'a>> for Command {
@@ -98,6 +156,9 @@ impl<'a> TryFrom<RawCommandData<'a>> for Command {
                 .map_err(|_| ConvertError::Length(data.len()))?;
             let rgb = RGB8::new(data[0], data[1], data[2]);
             Ok(Command::BoardLed(rgb))
+        } else if value.path == Command::BOARD_LED_SCRIPT {
+            let script = LedScript::decode(value.data.borrow())?;
+            Ok(Command::BoardLedScript(script))
         } else {
             Err(ConvertError::InvalidPath)
         }
@@ -107,7 +168,7 @@ impl<'a> TryFrom<RawCommandData<'a>> for Command {
 /// Handles `.data()` from EspMqttMessage
 ///
 // The message is a slice containing 3 values, and is cast into a ColorData(rgb)
-impl<'a> TryFrom<&[u8]> for ColorData {
+impl TryFrom<&[u8]> for ColorData {
     type Error = ConvertError;
 
     fn try_from(message: &[u8]) -> Result<Self, Self::Error> {
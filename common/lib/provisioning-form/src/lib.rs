@@ -0,0 +1,425 @@
+//! Hardware-independent parts of the board support crate's provisioning portal.
+//!
+//! The board support crate runs the access point and the HTTP server and
+//! stores the settings; the form they are entered in, making sense of what it
+//! sends, and the password of the access point live here, so they can be
+//! tested on the host.
+
+use std::fmt::Write as _;
+
+use anyhow::bail;
+
+/// What the portal asks for. Apps that don't use MQTT can ignore its part.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Settings {
+    pub wifi_ssid: String,
+    /// Empty for open networks
+    pub wifi_psk: String,
+    pub mqtt_host: String,
+    pub mqtt_port: u16,
+    /// Empty to connect without credentials
+    pub mqtt_user: String,
+    pub mqtt_pass: String,
+    pub mqtt_tls: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            wifi_ssid: String::new(),
+            wifi_psk: String::new(),
+            mqtt_host: String::new(),
+            mqtt_port: 1883,
+            mqtt_user: String::new(),
+            mqtt_pass: String::new(),
+            mqtt_tls: false,
+        }
+    }
+}
+
+impl Settings {
+    /// Parses and checks a submitted form, `application/x-www-form-urlencoded`.
+    ///
+    /// The form never shows passwords, so empty password fields keep those of
+    /// `previous`: the Wi-Fi one for the same network, the MQTT one for the
+    /// same user. An open network is chosen with its checkbox, and an empty
+    /// MQTT user drops the MQTT password, too.
+    pub fn from_form(body: &str, previous: &Settings) -> anyhow::Result<Self> {
+        let mut settings = Settings::default();
+        let mut wifi_open = false;
+        for pair in body.split('&').filter(|pair| !pair.is_empty()) {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let value = url_decode(value)?;
+            match name {
+                "wifi_ssid" => settings.wifi_ssid = value,
+                "wifi_psk" => settings.wifi_psk = value,
+                // checkboxes are only sent when checked
+                "wifi_open" => wifi_open = true,
+                "mqtt_host" => settings.mqtt_host = value.trim().to_string(),
+                "mqtt_port" if !value.trim().is_empty() => {
+                    settings.mqtt_port = match value.trim().parse() {
+                        Ok(port) => port,
+                        Err(_) => bail!("{} is not a port number", value),
+                    }
+                }
+                "mqtt_user" => settings.mqtt_user = value,
+                "mqtt_pass" => settings.mqtt_pass = value,
+                "mqtt_tls" => settings.mqtt_tls = true,
+                _ => {}
+            }
+        }
+
+        if settings.wifi_ssid.is_empty() || settings.wifi_ssid.len() > 32 {
+            bail!("the Wi-Fi name needs 1 to 32 characters");
+        }
+        if wifi_open {
+            if !settings.wifi_psk.is_empty() {
+                bail!("an open Wi-Fi network has no password");
+            }
+        } else {
+            if settings.wifi_psk.is_empty() {
+                if settings.wifi_ssid != previous.wifi_ssid || previous.wifi_psk.is_empty() {
+                    bail!("enter the Wi-Fi password, or check \"open network\"");
+                }
+                settings.wifi_psk = previous.wifi_psk.clone();
+            }
+            if !(8..=64).contains(&settings.wifi_psk.len()) {
+                bail!("the Wi-Fi password needs 8 to 64 characters");
+            }
+        }
+
+        if settings.mqtt_user.is_empty() {
+            settings.mqtt_pass.clear();
+        } else if settings.mqtt_pass.is_empty() && settings.mqtt_user == previous.mqtt_user {
+            settings.mqtt_pass = previous.mqtt_pass.clone();
+        }
+        Ok(settings)
+    }
+}
+
+/// Decodes `+` and `%XX` escapes
+pub fn url_decode(value: &str) -> anyhow::Result<String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut input = value.bytes();
+    while let Some(byte) = input.next() {
+        match byte {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let mut digit = || input.next().and_then(|digit| (digit as char).to_digit(16));
+                let decoded = match (digit(), digit()) {
+                    (Some(high), Some(low)) => Some((high * 16 + low) as u8),
+                    _ => None,
+                };
+                match decoded {
+                    Some(decoded) => bytes.push(decoded),
+                    None => bail!("invalid escape in form data"),
+                }
+            }
+            _ => bytes.push(byte),
+        }
+    }
+    Ok(String::from_utf8(bytes)?)
+}
+
+/// Escapes `text` for HTML text and attribute values
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// A whole page around `content`
+pub fn templated(content: impl AsRef<str>) -> String {
+    format!(
+        r#"
+<!DOCTYPE html>
+<html>
+    <head>
+        <meta charset="utf-8">
+        <meta name="viewport" content="width=device-width, initial-scale=1">
+        <title>esp-rs provisioning</title>
+    </head>
+    <body>
+        {}
+    </body>
+</html>
+"#,
+        content.as_ref()
+    )
+}
+
+/// How many characters `access_point_password` makes, within the 8 to 63 WPA2 allows
+pub const PASSWORD_LEN: usize = 12;
+
+/// Letters and digits that can't be mistaken for each other when read off the log
+const PASSWORD_CHARS: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+/// A password for the portal's access point, one character for each byte of `random`
+pub fn access_point_password(random: [u8; PASSWORD_LEN]) -> String {
+    random
+        .iter()
+        .map(|&byte| PASSWORD_CHARS[byte as usize % PASSWORD_CHARS.len()] as char)
+        .collect()
+}
+
+/// The form, filled in with `settings` except for the passwords
+pub fn form_html(settings: &Settings, error: Option<&str>) -> String {
+    let keep = |password: &str| {
+        if password.is_empty() {
+            ""
+        } else {
+            r#" placeholder="unchanged""#
+        }
+    };
+    let mut content = String::from("<h1>Board settings</h1>");
+    if let Some(error) = error {
+        let _ = write!(content, "<p><strong>{}</strong></p>", escape(error));
+    }
+    let _ = write!(
+        content,
+        r#"
+        <form method="post" action="/">
+            <h2>Wi-Fi</h2>
+            <p><label>Name <input name="wifi_ssid" value="{}" maxlength="32" required></label></p>
+            <p><label>Password <input name="wifi_psk" type="password" maxlength="64"{}></label></p>
+            <p><label><input name="wifi_open" type="checkbox"{}> Open network, without a password</label></p>
+            <h2>MQTT broker</h2>
+            <p><label>Host <input name="mqtt_host" value="{}"></label></p>
+            <p><label>Port <input name="mqtt_port" type="number" min="1" max="65535" value="{}"></label></p>
+            <p><label>User <input name="mqtt_user" value="{}"></label></p>
+            <p><label>Password <input name="mqtt_pass" type="password"{}></label></p>
+            <p><label><input name="mqtt_tls" type="checkbox"{}> TLS</label></p>
+            <p><button type="submit">Save and restart</button></p>
+        </form>"#,
+        escape(&settings.wifi_ssid),
+        keep(&settings.wifi_psk),
+        if !settings.wifi_ssid.is_empty() && settings.wifi_psk.is_empty() {
+            " checked"
+        } else {
+            ""
+        },
+        escape(&settings.mqtt_host),
+        settings.mqtt_port,
+        escape(&settings.mqtt_user),
+        keep(&settings.mqtt_pass),
+        if settings.mqtt_tls { " checked" } else { "" },
+    );
+    templated(content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stored() -> Settings {
+        Settings {
+            wifi_ssid: "office".into(),
+            wifi_psk: "hunter2hunter2".into(),
+            mqtt_host: "broker.local".into(),
+            mqtt_port: 8883,
+            mqtt_user: "horse".into(),
+            mqtt_pass: "CorrectHorseBatteryStaple".into(),
+            mqtt_tls: true,
+        }
+    }
+
+    fn error(body: &str, previous: &Settings) -> String {
+        match Settings::from_form(body, previous) {
+            Ok(_) => panic!("{} was accepted", body),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn decodes_escapes() {
+        assert_eq!(url_decode("a+b%20c%2B%26").unwrap(), "a b c+&");
+        assert_eq!(url_decode("%C3%A4%e2%82%ac").unwrap(), "ä€");
+        assert_eq!(url_decode("").unwrap(), "");
+    }
+
+    #[test]
+    fn access_point_passwords() {
+        assert_eq!(access_point_password([0; PASSWORD_LEN]), "aaaaaaaaaaaa");
+        let password = access_point_password([0, 1, 30, 31, 100, 200, 255, 7, 8, 9, 10, 11]);
+        assert_eq!(password, "ab9ahrhhjkmn");
+        assert!(password
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit()));
+        assert!(!password.contains(['l', 'o', '0', '1']));
+    }
+
+    #[test]
+    fn rejects_truncated_escapes() {
+        assert!(url_decode("%").is_err());
+        assert!(url_decode("abc%4").is_err());
+        assert!(url_decode("%zz").is_err());
+        assert!(url_decode("%+1").is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_utf8() {
+        assert!(url_decode("%C3").is_err());
+        assert!(url_decode("%FF%FE").is_err());
+        assert!(Settings::from_form("wifi_ssid=%FF&wifi_open=on", &Settings::default()).is_err());
+    }
+
+    #[test]
+    fn parses_everything() {
+        let settings = Settings::from_form(
+            "wifi_ssid=My+Wi-Fi&wifi_psk=s3cret%21%21&mqtt_host=+broker.local+&mqtt_port=8883\
+             &mqtt_user=horse&mqtt_pass=battery&mqtt_tls=on&unknown=1",
+            &Settings::default(),
+        )
+        .unwrap();
+        assert_eq!(settings.wifi_ssid, "My Wi-Fi");
+        assert_eq!(settings.wifi_psk, "s3cret!!");
+        assert_eq!(settings.mqtt_host, "broker.local");
+        assert_eq!(settings.mqtt_port, 8883);
+        assert_eq!(settings.mqtt_user, "horse");
+        assert_eq!(settings.mqtt_pass, "battery");
+        assert!(settings.mqtt_tls);
+    }
+
+    #[test]
+    fn unchecked_checkboxes() {
+        let settings = Settings::from_form(
+            "wifi_ssid=office&wifi_psk=&mqtt_host=broker.local&mqtt_port=&mqtt_user=horse&mqtt_pass=",
+            &stored(),
+        )
+        .unwrap();
+        // not sent, so not checked
+        assert!(!settings.mqtt_tls);
+        // not open, so the password stays
+        assert_eq!(settings.wifi_psk, "hunter2hunter2");
+        assert_eq!(settings.mqtt_port, Settings::default().mqtt_port);
+    }
+
+    #[test]
+    fn keeps_passwords() {
+        let settings =
+            Settings::from_form("wifi_ssid=office&mqtt_user=horse&mqtt_pass=", &stored()).unwrap();
+        assert_eq!(settings.wifi_psk, "hunter2hunter2");
+        assert_eq!(settings.mqtt_pass, "CorrectHorseBatteryStaple");
+    }
+
+    #[test]
+    fn replaces_passwords() {
+        let settings = Settings::from_form(
+            "wifi_ssid=office&wifi_psk=new+password&mqtt_user=horse&mqtt_pass=new",
+            &stored(),
+        )
+        .unwrap();
+        assert_eq!(settings.wifi_psk, "new password");
+        assert_eq!(settings.mqtt_pass, "new");
+    }
+
+    #[test]
+    fn other_network_needs_its_password() {
+        assert!(error("wifi_ssid=home", &stored()).contains("Wi-Fi password"));
+        let settings =
+            Settings::from_form("wifi_ssid=home&wifi_psk=home+password", &stored()).unwrap();
+        assert_eq!(settings.wifi_psk, "home password");
+    }
+
+    #[test]
+    fn open_network() {
+        let settings = Settings::from_form("wifi_ssid=office&wifi_open=on", &stored()).unwrap();
+        assert_eq!(settings.wifi_psk, "");
+        assert!(error(
+            "wifi_ssid=office&wifi_psk=hunter2hunter2&wifi_open=on",
+            &stored()
+        )
+        .contains("no password"));
+
+        // an open network stays open without checking the box again
+        let open = Settings {
+            wifi_psk: String::new(),
+            ..stored()
+        };
+        assert!(error("wifi_ssid=office", &open).contains("open network"));
+    }
+
+    #[test]
+    fn mqtt_password_goes_with_user() {
+        let settings =
+            Settings::from_form("wifi_ssid=office&mqtt_user=&mqtt_pass=", &stored()).unwrap();
+        assert_eq!(settings.mqtt_pass, "");
+
+        let settings =
+            Settings::from_form("wifi_ssid=office&mqtt_user=pony&mqtt_pass=", &stored()).unwrap();
+        assert_eq!(settings.mqtt_user, "pony");
+        assert_eq!(settings.mqtt_pass, "");
+    }
+
+    #[test]
+    fn ssid_length() {
+        assert!(error("wifi_ssid=&wifi_open=on", &stored()).contains("Wi-Fi name"));
+        let longest = "x".repeat(32);
+        let body = format!("wifi_ssid={}&wifi_open=on", longest);
+        assert_eq!(
+            Settings::from_form(&body, &stored()).unwrap().wifi_ssid,
+            longest
+        );
+        let body = format!("wifi_ssid={}x&wifi_open=on", longest);
+        assert!(error(&body, &stored()).contains("Wi-Fi name"));
+        // bytes, not characters
+        let body = format!("wifi_ssid={}&wifi_open=on", "%C3%A4".repeat(17));
+        assert!(error(&body, &stored()).contains("Wi-Fi name"));
+    }
+
+    #[test]
+    fn psk_length() {
+        assert!(error("wifi_ssid=office&wifi_psk=1234567", &stored()).contains("8 to 64"));
+        let body = format!("wifi_ssid=office&wifi_psk={}", "x".repeat(65));
+        assert!(error(&body, &stored()).contains("8 to 64"));
+        for len in [8, 64] {
+            let body = format!("wifi_ssid=office&wifi_psk={}", "x".repeat(len));
+            assert_eq!(
+                Settings::from_form(&body, &stored())
+                    .unwrap()
+                    .wifi_psk
+                    .len(),
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn port() {
+        assert!(error("wifi_ssid=office&mqtt_port=65536", &stored()).contains("port number"));
+        assert!(error("wifi_ssid=office&mqtt_port=mqtt", &stored()).contains("port number"));
+    }
+
+    #[test]
+    fn escapes_html() {
+        assert_eq!(
+            escape(r#"<a href="x">'&'</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;&#39;&amp;&#39;&lt;/a&gt;"
+        );
+    }
+
+    #[test]
+    fn form_hides_passwords() {
+        let html = form_html(&stored(), Some("<oops>"));
+        assert!(!html.contains("hunter2"));
+        assert!(!html.contains("CorrectHorse"));
+        assert!(html.contains(r#"placeholder="unchanged""#));
+        assert!(html.contains("&lt;oops&gt;"));
+        assert!(!html.contains(r#"name="wifi_open" type="checkbox" checked"#));
+
+        let open = Settings {
+            wifi_psk: String::new(),
+            ..stored()
+        };
+        assert!(form_html(&open, None).contains(r#"name="wifi_open" type="checkbox" checked"#));
+    }
+}
@@ -0,0 +1,182 @@
+//! Decodes eFuse fields the way ESP-IDF's `esp_efuse` component does.
+//!
+//! Fields are given as in `components/efuse/esp32c3/esp_efuse_table.csv`:
+//! the position of the first bit in their block and a length in bits.
+
+/// Position of a field in an eFuse block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Field {
+    /// Bit offset from the start of the block
+    pub bit: u32,
+    /// Length in bits, at most 32
+    pub len: u32,
+}
+
+impl Field {
+    /// Temperature sensor calibration, `EFUSE_BLK2, 131, 9`.
+    ///
+    /// Block 2 is read through `RD_SYS_PART1_DATA0..7`, so this is bits 3..12
+    /// of `RD_SYS_PART1_DATA4`. (esptool's older `mem_definition.py` placed it
+    /// at bit 7 of that word, which is off by four.)
+    pub const TEMP_CALIB: Field = Field { bit: 131, len: 9 };
+
+    /// Version of the calibration data in block 2, `EFUSE_BLK1, 120, 3`.
+    ///
+    /// Block 1 is read through `RD_MAC_SPI_SYS_0..5`, so this is bits 24..27
+    /// of `RD_MAC_SPI_SYS_3`.
+    pub const BLK_VERSION_MINOR: Field = Field { bit: 120, len: 3 };
+
+    /// Reads the field from the words of its block, least significant bit
+    /// first, like `esp_efuse_read_field_blob`. Fields may span two words.
+    ///
+    /// Panics if `block` is too short to hold the field.
+    pub fn read(&self, block: &[u32]) -> u32 {
+        assert!(
+            self.len <= 32,
+            "fields longer than 32 bits are not supported"
+        );
+        let mut value = 0u64;
+        for bit in (0..self.len).rev() {
+            let position = self.bit + bit;
+            let word = block[(position / 32) as usize];
+            value = value << 1 | ((word >> (position % 32)) & 1) as u64;
+        }
+        value as u32
+    }
+}
+
+/// Turns the raw `TEMP_CALIB` field into degrees Celsius.
+///
+/// Bit 8 is the sign, the lower 8 bits are the magnitude in tenths of a
+/// degree, as in `esp_efuse_rtc_calib_get_tsens_val`. The temperature
+/// sensor driver subtracts this from every reading.
+pub fn temp_calibration(raw: u32) -> f32 {
+    let magnitude = (raw & 0xff) as f32 / 10.;
+    if raw & (1 << 8) != 0 {
+        -magnitude
+    } else {
+        magnitude
+    }
+}
+
+/// The only `BLK_VERSION_MINOR` that `TEMP_CALIB` is valid for.
+pub const CALIBRATION_VERSION: u32 = 1;
+
+/// Reads and decodes the temperature sensor calibration from eFuse blocks 1
+/// and 2.
+///
+/// Like `esp_efuse_rtc_calib_get_tsens_val`, returns 0 if block 1 says
+/// block 2 holds no calibration data, or data of an unknown version.
+pub fn temp_calibration_from_blocks(block1: &[u32], block2: &[u32]) -> f32 {
+    if Field::BLK_VERSION_MINOR.read(block1) != CALIBRATION_VERSION {
+        return 0.;
+    }
+    temp_calibration(Field::TEMP_CALIB.read(block2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Block 1 with only `BLK_VERSION_MINOR` set, in `RD_MAC_SPI_SYS_3`
+    fn block1(version: u32) -> [u32; 6] {
+        let mut block = [0; 6];
+        block[3] = version << 24;
+        block
+    }
+
+    /// Block 2 with only `RD_SYS_PART1_DATA4` set
+    fn block2(data4: u32) -> [u32; 8] {
+        let mut block = [0; 8];
+        block[4] = data4;
+        block
+    }
+
+    fn calibration(data4: u32) -> f32 {
+        temp_calibration_from_blocks(&block1(CALIBRATION_VERSION), &block2(data4))
+    }
+
+    #[test]
+    fn uncalibrated() {
+        assert_eq!(calibration(0), 0.);
+    }
+
+    #[test]
+    fn version() {
+        // surrounded by WAFER_VERSION and PKG_VERSION below, reserved bits above
+        let mut block = block1(0b101);
+        block[3] |= 0xf800_0000 | 0x00ff_ffff;
+        assert_eq!(Field::BLK_VERSION_MINOR.read(&block), 0b101);
+    }
+
+    #[test]
+    fn unknown_version() {
+        // 2.5 degrees, if the version were right
+        let block2 = block2(0x0000_00c8);
+        for version in [0, 2, 7] {
+            assert_eq!(
+                temp_calibration_from_blocks(&block1(version), &block2),
+                0.,
+                "version {}",
+                version
+            );
+        }
+        assert_eq!(temp_calibration_from_blocks(&block1(1), &block2), 2.5);
+    }
+
+    #[test]
+    fn positive() {
+        // TEMP_CALIB = 0x019, surrounded by OCODE = 0x5a in bits 12..20
+        // and the lowest three bits set to 0b101
+        let raw = Field::TEMP_CALIB.read(&block2(0x0005_a0cd));
+        assert_eq!(raw, 0x019);
+        assert_eq!(temp_calibration(raw), 2.5);
+    }
+
+    #[test]
+    fn negative() {
+        // TEMP_CALIB = 0x10f, OCODE = 0x63
+        let raw = Field::TEMP_CALIB.read(&block2(0x0006_387a));
+        assert_eq!(raw, 0x10f);
+        assert_eq!(temp_calibration(raw), -1.5);
+    }
+
+    #[test]
+    fn negative_zero() {
+        assert_eq!(calibration(0x0000_0800), 0.);
+    }
+
+    #[test]
+    fn all_bits_set() {
+        let block = [u32::MAX; 8];
+        assert_eq!(Field::TEMP_CALIB.read(&block), 0x1ff);
+        assert_eq!(
+            temp_calibration_from_blocks(&block1(CALIBRATION_VERSION), &block),
+            -25.5
+        );
+    }
+
+    #[test]
+    fn ignores_other_words() {
+        let mut block = block2(0x0000_00c8);
+        block[3] = u32::MAX;
+        block[5] = u32::MAX;
+        assert_eq!(
+            temp_calibration_from_blocks(&block1(CALIBRATION_VERSION), &block),
+            2.5
+        );
+    }
+
+    #[test]
+    fn field_across_words() {
+        let field = Field { bit: 30, len: 4 };
+        // bits 30 and 31 of word 0 are the low bits, bits 0 and 1 of word 1 the high ones
+        assert_eq!(field.read(&[0b10 << 30, 0b01]), 0b0110);
+    }
+
+    #[test]
+    fn full_word() {
+        let field = Field { bit: 32, len: 32 };
+        assert_eq!(field.read(&[0, 0xdead_beef]), 0xdead_beef);
+    }
+}
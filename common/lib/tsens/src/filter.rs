@@ -0,0 +1,276 @@
+//! Turns noisy single conversions into steadier readings.
+//!
+//! Every reading is made from several conversions (oversampling). Conversions
+//! far from their median are dropped as outliers, and the rest are averaged.
+//! The readings are then smoothed with a moving average or an exponential
+//! filter, and statistics over the last few readings show how noisy they are.
+
+use std::collections::VecDeque;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Smoothing {
+    None,
+    /// Mean of the last `n` readings
+    MovingAverage(usize),
+    /// `smoothed += alpha * (reading - smoothed)`, with `alpha` in 0.0..=1.0.
+    /// Smaller values smooth more.
+    Exponential(f32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FilterConfig {
+    /// Conversions per reading, at least 1
+    pub oversampling: usize,
+    /// Conversions further than this from the median are dropped
+    pub max_deviation: Option<f32>,
+    pub smoothing: Smoothing,
+    /// Number of readings the statistics are taken over
+    pub window: usize,
+}
+
+/// Single conversions, unsmoothed: what the sensor did before filtering.
+impl Default for FilterConfig {
+    fn default() -> Self {
+        Self {
+            oversampling: 1,
+            max_deviation: None,
+            smoothing: Smoothing::None,
+            window: 10,
+        }
+    }
+}
+
+impl FilterConfig {
+    pub fn oversampling(mut self, oversampling: usize) -> Self {
+        self.oversampling = oversampling.max(1);
+        self
+    }
+
+    pub fn max_deviation(mut self, max_deviation: Option<f32>) -> Self {
+        self.max_deviation = max_deviation;
+        self
+    }
+
+    pub fn smoothing(mut self, smoothing: Smoothing) -> Self {
+        self.smoothing = smoothing;
+        self
+    }
+
+    pub fn window(mut self, window: usize) -> Self {
+        self.window = window.max(1);
+        self
+    }
+}
+
+/// Statistics over the readings in the filter's window, before smoothing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Statistics {
+    pub count: usize,
+    pub min: f32,
+    pub max: f32,
+    pub mean: f32,
+    /// Population standard deviation
+    pub std_dev: f32,
+}
+
+impl Statistics {
+    /// `None` if there are no `values`
+    pub fn of(values: impl IntoIterator<Item = f32> + Clone) -> Option<Self> {
+        let mut count = 0;
+        let mut min = f32::INFINITY;
+        let mut max = f32::NEG_INFINITY;
+        let mut sum = 0.;
+        for value in values.clone() {
+            count += 1;
+            min = min.min(value);
+            max = max.max(value);
+            sum += value;
+        }
+        if count == 0 {
+            return None;
+        }
+        let mean = sum / count as f32;
+        let variance = values
+            .into_iter()
+            .map(|value| (value - mean) * (value - mean))
+            .sum::<f32>()
+            / count as f32;
+        Some(Self {
+            count,
+            min,
+            max,
+            mean,
+            std_dev: variance.sqrt(),
+        })
+    }
+}
+
+/// Median of `values`, which get sorted. The mean of the middle two for an even count.
+///
+/// Panics if `values` is empty.
+pub fn median(values: &mut [f32]) -> f32 {
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let middle = values.len() / 2;
+    if middle * 2 == values.len() {
+        (values[middle - 1] + values[middle]) / 2.
+    } else {
+        values[middle]
+    }
+}
+
+/// Mean of the `values` that are at most `max_deviation` away from their median.
+/// If that leaves none, the median.
+///
+/// Panics if `values` is empty.
+pub fn mean_without_outliers(values: &mut [f32], max_deviation: f32) -> f32 {
+    let median = median(values);
+    let kept = values
+        .iter()
+        .filter(|&&value| (value - median).abs() <= max_deviation);
+    Statistics::of(kept.copied()).map_or(median, |kept| kept.mean)
+}
+
+pub struct Filter {
+    config: FilterConfig,
+    readings: VecDeque<f32>,
+    smoothed: Option<f32>,
+}
+
+impl Filter {
+    pub fn new(config: FilterConfig) -> Self {
+        Self {
+            config,
+            readings: VecDeque::new(),
+            smoothed: None,
+        }
+    }
+
+    pub fn config(&self) -> &FilterConfig {
+        &self.config
+    }
+
+    /// Combines the conversions of one reading and returns the smoothed value.
+    ///
+    /// Panics if `conversions` is empty.
+    pub fn push(&mut self, conversions: &mut [f32]) -> f32 {
+        let reading = match self.config.max_deviation {
+            Some(max_deviation) => mean_without_outliers(conversions, max_deviation),
+            None => conversions.iter().sum::<f32>() / conversions.len() as f32,
+        };
+
+        self.readings.push_back(reading);
+        let keep = self.config.window.max(match self.config.smoothing {
+            Smoothing::MovingAverage(n) => n,
+            _ => 1,
+        });
+        while self.readings.len() > keep {
+            self.readings.pop_front();
+        }
+
+        let smoothed = match self.config.smoothing {
+            Smoothing::None => reading,
+            Smoothing::MovingAverage(n) => {
+                let n = n.clamp(1, self.readings.len());
+                self.readings.iter().rev().take(n).sum::<f32>() / n as f32
+            }
+            Smoothing::Exponential(alpha) => match self.smoothed {
+                Some(smoothed) => smoothed + alpha.clamp(0., 1.) * (reading - smoothed),
+                None => reading,
+            },
+        };
+        self.smoothed = Some(smoothed);
+        smoothed
+    }
+
+    /// Statistics over the last `window` readings, `None` before the first one.
+    pub fn statistics(&self) -> Option<Statistics> {
+        let skip = self.readings.len().saturating_sub(self.config.window);
+        Statistics::of(self.readings.iter().skip(skip).copied())
+    }
+
+    /// Forgets all readings, e.g. after the sensor has been reconfigured.
+    pub fn reset(&mut self) {
+        self.readings.clear();
+        self.smoothed = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-4
+    }
+
+    #[test]
+    fn medians() {
+        assert_eq!(median(&mut [3., 1., 2.]), 2.);
+        assert_eq!(median(&mut [4., 1., 3., 2.]), 2.5);
+        assert_eq!(median(&mut [7.]), 7.);
+    }
+
+    #[test]
+    fn drops_outliers() {
+        let mut conversions = [25.0, 25.4, 24.6, 60.0, 25.0, -3.0];
+        assert!(close(mean_without_outliers(&mut conversions, 1.), 25.));
+    }
+
+    #[test]
+    fn falls_back_to_median() {
+        let mut conversions = [10., 20.];
+        assert_eq!(mean_without_outliers(&mut conversions, 1.), 15.);
+    }
+
+    #[test]
+    fn statistics() {
+        let stats = Statistics::of([2., 4., 4., 4., 5., 5., 7., 9.]).unwrap();
+        assert_eq!(stats.count, 8);
+        assert_eq!(stats.min, 2.);
+        assert_eq!(stats.max, 9.);
+        assert_eq!(stats.mean, 5.);
+        assert_eq!(stats.std_dev, 2.);
+        assert!(Statistics::of([]).is_none());
+    }
+
+    #[test]
+    fn unfiltered_by_default() {
+        let mut filter = Filter::new(FilterConfig::default());
+        assert_eq!(filter.push(&mut [21.]), 21.);
+        assert_eq!(filter.push(&mut [23.]), 23.);
+        assert_eq!(filter.statistics().unwrap().mean, 22.);
+    }
+
+    #[test]
+    fn oversampling_averages() {
+        let mut filter = Filter::new(FilterConfig::default().oversampling(4));
+        assert_eq!(filter.push(&mut [20., 21., 22., 23.]), 21.5);
+    }
+
+    #[test]
+    fn moving_average() {
+        let config = FilterConfig::default()
+            .smoothing(Smoothing::MovingAverage(3))
+            .window(2);
+        let mut filter = Filter::new(config);
+        assert_eq!(filter.push(&mut [3.]), 3.);
+        assert_eq!(filter.push(&mut [6.]), 4.5);
+        assert_eq!(filter.push(&mut [9.]), 6.);
+        assert_eq!(filter.push(&mut [12.]), 9.);
+        // statistics only cover the window, though three readings are kept
+        let stats = filter.statistics().unwrap();
+        assert_eq!((stats.count, stats.min, stats.max), (2, 9., 12.));
+    }
+
+    #[test]
+    fn exponential() {
+        let mut filter =
+            Filter::new(FilterConfig::default().smoothing(Smoothing::Exponential(0.5)));
+        assert_eq!(filter.push(&mut [10.]), 10.);
+        assert_eq!(filter.push(&mut [20.]), 15.);
+        assert_eq!(filter.push(&mut [20.]), 17.5);
+        filter.reset();
+        assert_eq!(filter.push(&mut [0.]), 0.);
+        assert_eq!(filter.statistics().unwrap().count, 1);
+    }
+}
@@ -0,0 +1,8 @@
+//! Hardware-independent parts of the ESP32-C3 temperature sensor driver.
+//!
+//! The board support crate reads the registers; everything that only turns
+//! numbers into other numbers lives here, so it can be tested on the host.
+
+pub mod efuse;
+pub mod filter;
+pub mod range;
@@ -0,0 +1,194 @@
+//! Measurement ranges of the temperature sensor and switching between them.
+//!
+//! The sensor only covers part of its -40..125℃ span at a time. Which part
+//! is chosen by the DAC offset; ranges closer to room temperature are more
+//! accurate. Values are from ESP-IDF's `temp_sensor.c`.
+
+use std::ops::RangeInclusive;
+
+const ADC_FACTOR: f32 = 0.4386;
+const DAC_FACTOR: f32 = 27.88;
+
+/// A measurement range. The discriminant is the value of the
+/// `I2C_SARADC_TSENS_DAC` register that selects it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DacOffset {
+    /// 50℃ ~ 125℃, error < 3℃
+    L0 = 5,
+    /// 20℃ ~ 100℃, error < 2℃
+    L1 = 7,
+    /// -10℃ ~ 80℃, error < 1℃
+    L2 = 15,
+    /// -30℃ ~ 50℃, error < 2℃
+    L3 = 11,
+    /// -40℃ ~ 20℃, error < 3℃
+    L4 = 10,
+}
+
+// `#[default]` on enum variants is too new for the ESP toolchain
+#[allow(clippy::derivable_impls)]
+impl Default for DacOffset {
+    fn default() -> Self {
+        DacOffset::L2
+    }
+}
+
+impl DacOffset {
+    /// Most accurate first
+    pub const BY_ACCURACY: [DacOffset; 5] = [
+        DacOffset::L2,
+        DacOffset::L1,
+        DacOffset::L3,
+        DacOffset::L0,
+        DacOffset::L4,
+    ];
+
+    pub fn offset(self) -> i8 {
+        match self {
+            DacOffset::L0 => -2,
+            DacOffset::L1 => -1,
+            DacOffset::L2 => 0,
+            DacOffset::L3 => 1,
+            DacOffset::L4 => 2,
+        }
+    }
+
+    /// Value to write to the `I2C_SARADC_TSENS_DAC` register
+    pub fn register_value(self) -> u8 {
+        self as u8
+    }
+
+    /// Temperatures this range can measure, in ℃
+    pub fn range(self) -> RangeInclusive<f32> {
+        match self {
+            DacOffset::L0 => 50.0..=125.0,
+            DacOffset::L1 => 20.0..=100.0,
+            DacOffset::L2 => -10.0..=80.0,
+            DacOffset::L3 => -30.0..=50.0,
+            DacOffset::L4 => -40.0..=20.0,
+        }
+    }
+
+    /// Largest error within the range, in ℃
+    pub fn max_error(self) -> f32 {
+        match self {
+            DacOffset::L2 => 1.,
+            DacOffset::L1 | DacOffset::L3 => 2.,
+            DacOffset::L0 | DacOffset::L4 => 3.,
+        }
+    }
+
+    /// Converts a `TSENS_OUT` reading taken in this range to ℃.
+    ///
+    /// `calibration` is the eFuse value from `efuse::temp_calibration`.
+    pub fn to_celsius(self, tsens_out: u8, calibration: f32) -> f32 {
+        ADC_FACTOR * tsens_out as f32 - DAC_FACTOR * self.offset() as f32 - calibration
+    }
+
+    /// Whether `celsius` is in range, and at least `margin` away from its edges
+    fn covers(self, celsius: f32, margin: f32) -> bool {
+        let range = self.range();
+        *range.start() + margin <= celsius && celsius <= *range.end() - margin
+    }
+}
+
+/// Picks the most accurate range for the temperature that was just read.
+///
+/// It leaves the current range once a reading comes within `margin` of its
+/// edge, and only switches to a more accurate range once the reading is
+/// `2 * margin` inside of it, so noise around an edge doesn't make it flip
+/// back and forth.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AutoRange {
+    pub margin: f32,
+}
+
+impl Default for AutoRange {
+    fn default() -> Self {
+        Self { margin: 5. }
+    }
+}
+
+impl AutoRange {
+    pub fn new(margin: f32) -> Self {
+        Self { margin }
+    }
+
+    /// The range to read the next value in, given `celsius` read in `current`
+    pub fn next(&self, current: DacOffset, celsius: f32) -> DacOffset {
+        let better = DacOffset::BY_ACCURACY
+            .iter()
+            .take_while(|&&range| range != current)
+            .find(|range| range.covers(celsius, 2. * self.margin));
+        if let Some(&better) = better {
+            return better;
+        }
+        if current.covers(celsius, self.margin) {
+            return current;
+        }
+        DacOffset::BY_ACCURACY
+            .iter()
+            .copied()
+            .find(|range| range.covers(celsius, self.margin))
+            // beyond all ranges: stay at the end that is closest
+            .unwrap_or(if celsius > 0. {
+                DacOffset::L0
+            } else {
+                DacOffset::L4
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conversion() {
+        assert_eq!(DacOffset::L2.to_celsius(0, 0.), 0.);
+        assert!((DacOffset::L2.to_celsius(57, 0.) - 25.).abs() < 0.01);
+        assert!((DacOffset::L1.to_celsius(57, 0.) - 52.88).abs() < 0.01);
+        assert!((DacOffset::L2.to_celsius(57, -1.5) - 26.5).abs() < 0.01);
+    }
+
+    #[test]
+    fn stays_in_range() {
+        let auto = AutoRange::default();
+        assert_eq!(auto.next(DacOffset::L2, 25.), DacOffset::L2);
+        assert_eq!(auto.next(DacOffset::L2, 74.), DacOffset::L2);
+        assert_eq!(auto.next(DacOffset::L2, -4.), DacOffset::L2);
+    }
+
+    #[test]
+    fn leaves_near_edge() {
+        let auto = AutoRange::default();
+        assert_eq!(auto.next(DacOffset::L2, 76.), DacOffset::L1);
+        assert_eq!(auto.next(DacOffset::L2, -6.), DacOffset::L3);
+        assert_eq!(auto.next(DacOffset::L1, 96.), DacOffset::L0);
+        assert_eq!(auto.next(DacOffset::L3, -26.), DacOffset::L4);
+    }
+
+    #[test]
+    fn returns_with_hysteresis() {
+        let auto = AutoRange::default();
+        // within L2 by more than the margin, but not twice the margin
+        assert_eq!(auto.next(DacOffset::L1, 74.), DacOffset::L1);
+        assert_eq!(auto.next(DacOffset::L1, 69.), DacOffset::L2);
+        assert_eq!(auto.next(DacOffset::L0, 89.), DacOffset::L1);
+    }
+
+    #[test]
+    fn jumps_across_ranges() {
+        let auto = AutoRange::default();
+        assert_eq!(auto.next(DacOffset::L4, 110.), DacOffset::L0);
+        assert_eq!(auto.next(DacOffset::L0, 25.), DacOffset::L2);
+    }
+
+    #[test]
+    fn beyond_all_ranges() {
+        let auto = AutoRange::default();
+        assert_eq!(auto.next(DacOffset::L0, 124.), DacOffset::L0);
+        assert_eq!(auto.next(DacOffset::L2, 130.), DacOffset::L0);
+        assert_eq!(auto.next(DacOffset::L4, -39.), DacOffset::L4);
+    }
+}
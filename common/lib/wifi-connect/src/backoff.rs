@@ -0,0 +1,61 @@
+//! How long to wait before reconnecting after the connection dropped.
+
+use std::time::Duration;
+
+/// The pause after the first drop
+pub const MIN_PAUSE: Duration = Duration::from_secs(1);
+/// The longest pause, however often reconnecting fails
+pub const MAX_PAUSE: Duration = Duration::from_secs(60);
+
+/// Doubles the pause with every failed reconnection, from `MIN_PAUSE` up to `MAX_PAUSE`.
+#[derive(Debug)]
+pub struct Backoff {
+    next: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Backoff {
+    pub fn new() -> Self {
+        Self { next: MIN_PAUSE }
+    }
+
+    /// The pause before the next attempt
+    pub fn next_pause(&mut self) -> Duration {
+        let pause = self.next;
+        self.next = (self.next * 2).min(MAX_PAUSE);
+        pause
+    }
+
+    /// After getting an IP address
+    pub fn reset(&mut self) {
+        self.next = MIN_PAUSE;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pauses(backoff: &mut Backoff, count: usize) -> Vec<u64> {
+        (0..count).map(|_| backoff.next_pause().as_secs()).collect()
+    }
+
+    #[test]
+    fn doubles_up_to_a_minute() {
+        let mut backoff = Backoff::new();
+        assert_eq!(pauses(&mut backoff, 9), [1, 2, 4, 8, 16, 32, 60, 60, 60]);
+    }
+
+    #[test]
+    fn reset_starts_over() {
+        let mut backoff = Backoff::new();
+        pauses(&mut backoff, 4);
+        backoff.reset();
+        assert_eq!(pauses(&mut backoff, 3), [1, 2, 4]);
+    }
+}
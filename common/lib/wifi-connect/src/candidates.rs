@@ -0,0 +1,103 @@
+//! The order in which `wifi_multi` tries the networks it knows.
+
+/// A network `wifi_multi` may connect to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub ssid: String,
+    /// Empty for open networks
+    pub psk: String,
+}
+
+impl Credentials {
+    pub fn new(ssid: impl Into<String>, psk: impl Into<String>) -> Self {
+        Self {
+            ssid: ssid.into(),
+            psk: psk.into(),
+        }
+    }
+}
+
+/// `networks` in the order to try them, each with its strongest access
+/// point if the scan found it.
+///
+/// Networks the scan found come first, then the others, both in the order
+/// of `networks`. `describe` returns the SSID and signal strength of an
+/// access point.
+pub fn candidates<'a, A>(
+    networks: &'a [Credentials],
+    access_points: &'a [A],
+    describe: impl Fn(&A) -> (&str, u8),
+) -> Vec<(&'a Credentials, Option<&'a A>)> {
+    let (mut found, not_found): (Vec<_>, Vec<_>) = networks
+        .iter()
+        .map(|network| {
+            let strongest = access_points
+                .iter()
+                .filter(|ap| describe(ap).0 == network.ssid)
+                .max_by_key(|ap| describe(ap).1);
+            (network, strongest)
+        })
+        .partition(|(_, ap)| ap.is_some());
+    found.extend(not_found);
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// SSID and signal strength
+    type Ap = (&'static str, u8);
+
+    fn order(networks: &[Credentials], aps: &[Ap]) -> Vec<(String, Option<u8>)> {
+        candidates(networks, aps, |&(ssid, strength)| (ssid, strength))
+            .into_iter()
+            .map(|(network, ap)| (network.ssid.clone(), ap.map(|ap| ap.1)))
+            .collect()
+    }
+
+    fn networks(ssids: &[&str]) -> Vec<Credentials> {
+        ssids.iter().map(|ssid| Credentials::new(*ssid, "")).collect()
+    }
+
+    #[test]
+    fn preferred_found_first() {
+        let networks = networks(&["office", "home"]);
+        let aps = [("home", 70), ("office", 30)];
+        assert_eq!(
+            order(&networks, &aps),
+            [("office".into(), Some(30)), ("home".into(), Some(70))]
+        );
+    }
+
+    #[test]
+    fn unseen_last() {
+        let networks = networks(&["hidden", "office", "gone", "home"]);
+        let aps = [("home", 50), ("office", 40), ("neighbour", 90)];
+        assert_eq!(
+            order(&networks, &aps),
+            [
+                ("office".into(), Some(40)),
+                ("home".into(), Some(50)),
+                ("hidden".into(), None),
+                ("gone".into(), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn strongest_access_point() {
+        let networks = networks(&["office"]);
+        let aps = [("office", 20), ("office", 80), ("office", 50)];
+        assert_eq!(order(&networks, &aps), [("office".into(), Some(80))]);
+    }
+
+    #[test]
+    fn nothing_found() {
+        let networks = networks(&["office", "home"]);
+        assert_eq!(
+            order(&networks, &[]),
+            [("office".into(), None), ("home".into(), None)]
+        );
+    }
+}
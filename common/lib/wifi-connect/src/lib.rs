@@ -0,0 +1,10 @@
+//! Hardware-independent parts of the board support crate's Wi-Fi connection.
+//!
+//! The board support crate registers for the system events and talks to
+//! `EspWifi`; picking the network to connect to, keeping track of what the
+//! events mean and how long to wait before reconnecting lives here, so it can
+//! be tested on the host.
+
+pub mod backoff;
+pub mod candidates;
+pub mod state;
@@ -0,0 +1,215 @@
+//! What the Wi-Fi and IP events say about the connection.
+//!
+//! The events are only followed here; when to reconnect after a drop is up to
+//! `backoff`.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WifiState {
+    /// Associating with the access point or waiting for an IP address
+    Connecting,
+    /// Has an IP address
+    Connected,
+    /// Lost the access point; reconnecting after a pause
+    Disconnected,
+}
+
+/// The system events the connection follows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Associated,
+    /// `rejected` if trying the same network again won't help, e.g. because
+    /// it refused the password
+    Disconnected { rejected: bool },
+    GotIp,
+    LostIp,
+    /// Marks where the events of the attempt after `Tracker::begin_attempt`
+    /// start; everything before it belongs to earlier ones
+    AttemptStarted,
+}
+
+/// How connecting to one network went
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Attempt {
+    Connected,
+    Rejected,
+}
+
+#[derive(Debug)]
+pub struct Tracker {
+    state: WifiState,
+    /// Between `begin_attempt` and `Event::AttemptStarted`, while the events
+    /// still belong to an earlier attempt
+    stale: bool,
+    /// Got an IP address since the attempt started
+    connected: bool,
+    /// The access point refused us since the attempt started
+    rejected: bool,
+}
+
+impl Default for Tracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Tracker {
+    pub fn new() -> Self {
+        Self {
+            state: WifiState::Connecting,
+            stale: false,
+            connected: false,
+            rejected: false,
+        }
+    }
+
+    pub fn state(&self) -> WifiState {
+        self.state
+    }
+
+    /// Returns the new state, if `event` changed it.
+    pub fn handle(&mut self, event: Event) -> Option<WifiState> {
+        let state = match event {
+            Event::Associated | Event::LostIp => WifiState::Connecting,
+            Event::GotIp => {
+                self.connected |= !self.stale;
+                WifiState::Connected
+            }
+            Event::Disconnected { rejected } => {
+                self.rejected |= rejected && !self.stale;
+                WifiState::Disconnected
+            }
+            Event::AttemptStarted => {
+                self.stale = false;
+                self.connected = false;
+                self.rejected = false;
+                return None;
+            }
+        };
+        if state == self.state {
+            return None;
+        }
+        self.state = state;
+        Some(state)
+    }
+
+    /// Forgets how the last attempt went, before connecting to another
+    /// network. Until `Event::AttemptStarted`, events don't count for the
+    /// new attempt.
+    pub fn begin_attempt(&mut self) {
+        self.stale = true;
+        self.connected = false;
+        self.rejected = false;
+    }
+
+    /// How the attempt since `begin_attempt` went, `None` while it is still going.
+    pub fn attempt(&self) -> Option<Attempt> {
+        if self.state == WifiState::Connected && self.connected {
+            Some(Attempt::Connected)
+        } else if self.rejected {
+            Some(Attempt::Rejected)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DROPPED: Event = Event::Disconnected { rejected: false };
+    const REFUSED: Event = Event::Disconnected { rejected: true };
+
+    #[test]
+    fn connects() {
+        let mut tracker = Tracker::new();
+        assert_eq!(tracker.state(), WifiState::Connecting);
+        assert_eq!(tracker.handle(Event::Associated), None);
+        assert_eq!(tracker.attempt(), None);
+        assert_eq!(tracker.handle(Event::GotIp), Some(WifiState::Connected));
+        assert_eq!(tracker.attempt(), Some(Attempt::Connected));
+    }
+
+    #[test]
+    fn drops_and_comes_back() {
+        let mut tracker = Tracker::new();
+        tracker.handle(Event::GotIp);
+
+        assert_eq!(tracker.handle(DROPPED), Some(WifiState::Disconnected));
+        // every failed reconnection is another disconnect
+        assert_eq!(tracker.handle(DROPPED), None);
+        assert_eq!(
+            tracker.handle(Event::Associated),
+            Some(WifiState::Connecting)
+        );
+        assert_eq!(tracker.handle(Event::GotIp), Some(WifiState::Connected));
+    }
+
+    #[test]
+    fn loses_ip() {
+        let mut tracker = Tracker::new();
+        tracker.handle(Event::GotIp);
+        assert_eq!(tracker.handle(Event::LostIp), Some(WifiState::Connecting));
+        assert_eq!(tracker.attempt(), None);
+    }
+
+    #[test]
+    fn drop_is_no_rejection() {
+        let mut tracker = Tracker::new();
+        tracker.handle(DROPPED);
+        assert_eq!(tracker.state(), WifiState::Disconnected);
+        assert_eq!(tracker.attempt(), None);
+    }
+
+    #[test]
+    fn rejection_lasts_until_next_attempt() {
+        let mut tracker = Tracker::new();
+        tracker.handle(REFUSED);
+        assert_eq!(tracker.attempt(), Some(Attempt::Rejected));
+
+        // a later drop doesn't make it less of a rejection
+        tracker.handle(Event::Associated);
+        tracker.handle(DROPPED);
+        assert_eq!(tracker.attempt(), Some(Attempt::Rejected));
+
+        tracker.begin_attempt();
+        assert_eq!(tracker.attempt(), None);
+    }
+
+    #[test]
+    fn earlier_attempts_dont_count() {
+        let mut tracker = Tracker::new();
+        tracker.handle(DROPPED);
+        tracker.begin_attempt();
+
+        // still from the network tried before
+        tracker.handle(REFUSED);
+        assert_eq!(tracker.attempt(), None);
+        tracker.handle(Event::GotIp);
+        assert_eq!(tracker.attempt(), None);
+
+        assert_eq!(tracker.handle(Event::AttemptStarted), None);
+        assert_eq!(tracker.attempt(), None);
+        tracker.handle(DROPPED);
+        tracker.handle(Event::Associated);
+        assert_eq!(tracker.handle(Event::GotIp), Some(WifiState::Connected));
+        assert_eq!(tracker.attempt(), Some(Attempt::Connected));
+    }
+
+    #[test]
+    fn rejection_after_attempt_started() {
+        let mut tracker = Tracker::new();
+        tracker.begin_attempt();
+        tracker.handle(Event::AttemptStarted);
+        tracker.handle(REFUSED);
+        assert_eq!(tracker.attempt(), Some(Attempt::Rejected));
+    }
+
+    #[test]
+    fn connection_beats_rejection() {
+        let mut tracker = Tracker::new();
+        tracker.handle(REFUSED);
+        tracker.handle(Event::GotIp);
+        assert_eq!(tracker.attempt(), Some(Attempt::Connected));
+    }
+}
@@ -0,0 +1,392 @@
+//! Encodes pixel data for WS2812 and similar one-wire LEDs into pulses.
+//!
+//! Every bit is sent as a high pulse followed by a low pulse; their lengths
+//! tell a one from a zero. `Encoder` turns bytes into these
+//! (level, duration) pairs for a given clock, e.g. the RMT tick of an
+//! ESP32-C3, without touching any hardware, so it can be tested on the host.
+
+use std::fmt;
+use std::time::Duration;
+
+pub use rgb::RGB8;
+
+/// Longest pulse an RMT item can hold, in ticks
+pub const MAX_TICKS: u16 = (1 << 15) - 1;
+
+/// Pulse lengths of a one-wire LED protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timing {
+    pub t0h_ns: u32,
+    pub t0l_ns: u32,
+    pub t1h_ns: u32,
+    pub t1l_ns: u32,
+    /// How long the data line has to stay low for the LEDs to latch a frame
+    pub latch: Duration,
+}
+
+impl Timing {
+    /// The LED on the ESP32-C3-DevKitC-02 board
+    pub const WS2812: Timing = Timing {
+        t0h_ns: 350,
+        t0l_ns: 1000,
+        t1h_ns: 1000,
+        t1l_ns: 350,
+        latch: Duration::from_micros(280),
+    };
+
+    /// Older WS2812B only need a 50µs latch, current ones 280µs.
+    pub const WS2812B: Timing = Timing {
+        t0h_ns: 400,
+        t0l_ns: 850,
+        t1h_ns: 800,
+        t1l_ns: 450,
+        latch: Duration::from_micros(280),
+    };
+
+    /// WS2811 in high speed (800kHz) mode
+    pub const WS2811: Timing = Timing {
+        t0h_ns: 250,
+        t0l_ns: 1000,
+        t1h_ns: 600,
+        t1l_ns: 650,
+        latch: Duration::from_micros(280),
+    };
+
+    pub const SK6812: Timing = Timing {
+        t0h_ns: 300,
+        t0l_ns: 900,
+        t1h_ns: 600,
+        t1l_ns: 600,
+        latch: Duration::from_micros(80),
+    };
+
+    pub const APA106: Timing = Timing {
+        t0h_ns: 350,
+        t0l_ns: 1360,
+        t1h_ns: 1360,
+        t1l_ns: 350,
+        latch: Duration::from_micros(50),
+    };
+}
+
+impl Default for Timing {
+    fn default() -> Self {
+        Timing::WS2812
+    }
+}
+
+/// The order in which an LED expects its color channels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorOrder {
+    Rgb,
+    Grb,
+    /// Green, red, blue and a separate white LED
+    Grbw,
+}
+
+impl ColorOrder {
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            ColorOrder::Rgb | ColorOrder::Grb => 3,
+            ColorOrder::Grbw => 4,
+        }
+    }
+
+    /// Appends `color` to `data` in this order. `white` is only sent to RGBW LEDs.
+    pub fn extend(self, color: RGB8, white: u8, data: &mut Vec<u8>) {
+        let (bytes, len) = self.arrange(color, white);
+        data.extend_from_slice(&bytes[..len]);
+    }
+
+    fn arrange(self, color: RGB8, white: u8) -> ([u8; 4], usize) {
+        let RGB8 { r, g, b } = color;
+        match self {
+            ColorOrder::Rgb => ([r, g, b, 0], 3),
+            ColorOrder::Grb => ([g, r, b, 0], 3),
+            ColorOrder::Grbw => ([g, r, b, white], 4),
+        }
+    }
+}
+
+/// LED chips with known timing and color order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LedChip {
+    Ws2812,
+    Ws2812b,
+    Ws2811,
+    Sk6812Rgbw,
+    Apa106,
+}
+
+impl LedChip {
+    pub fn timing(self) -> Timing {
+        match self {
+            LedChip::Ws2812 => Timing::WS2812,
+            LedChip::Ws2812b => Timing::WS2812B,
+            LedChip::Ws2811 => Timing::WS2811,
+            LedChip::Sk6812Rgbw => Timing::SK6812,
+            LedChip::Apa106 => Timing::APA106,
+        }
+    }
+
+    pub fn color_order(self) -> ColorOrder {
+        match self {
+            LedChip::Ws2812 | LedChip::Ws2812b => ColorOrder::Grb,
+            LedChip::Ws2811 | LedChip::Apa106 => ColorOrder::Rgb,
+            LedChip::Sk6812Rgbw => ColorOrder::Grbw,
+        }
+    }
+}
+
+/// The data line at `level` for `ticks` clock cycles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pulse {
+    pub level: bool,
+    pub ticks: u16,
+}
+
+impl Pulse {
+    pub const fn new(level: bool, ticks: u16) -> Self {
+        Self { level, ticks }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// A pulse of `ns` nanoseconds is shorter than one tick or longer than
+    /// `MAX_TICKS` at `clock_hz`.
+    PulseOutOfRange { ns: u32, clock_hz: u32 },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::PulseOutOfRange { ns, clock_hz } => write!(
+                f,
+                "a {}ns pulse can't be produced with a {}Hz clock",
+                ns, clock_hz
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Turns pixel data into pulses for one timing and color order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Encoder {
+    zero: [Pulse; 2],
+    one: [Pulse; 2],
+    color_order: ColorOrder,
+    latch: Duration,
+}
+
+impl Encoder {
+    pub fn new(chip: LedChip, clock_hz: u32) -> Result<Self, Error> {
+        Self::with_timing(&chip.timing(), chip.color_order(), clock_hz)
+    }
+
+    pub fn with_timing(
+        timing: &Timing,
+        color_order: ColorOrder,
+        clock_hz: u32,
+    ) -> Result<Self, Error> {
+        let pulse = |level, ns| Ok(Pulse::new(level, ticks(ns, clock_hz)?));
+        Ok(Self {
+            zero: [pulse(true, timing.t0h_ns)?, pulse(false, timing.t0l_ns)?],
+            one: [pulse(true, timing.t1h_ns)?, pulse(false, timing.t1l_ns)?],
+            color_order,
+            latch: timing.latch,
+        })
+    }
+
+    pub fn color_order(&self) -> ColorOrder {
+        self.color_order
+    }
+
+    pub fn latch(&self) -> Duration {
+        self.latch
+    }
+
+    /// The high and the low pulse of one bit.
+    pub fn bit(&self, bit: bool) -> [Pulse; 2] {
+        if bit {
+            self.one
+        } else {
+            self.zero
+        }
+    }
+
+    /// The pulses of one byte, most significant bit first.
+    pub fn byte(&self, byte: u8) -> [[Pulse; 2]; 8] {
+        let mut bits = [self.zero; 8];
+        for (i, bit) in bits.iter_mut().enumerate() {
+            *bit = self.bit(byte & (0x80 >> i) != 0);
+        }
+        bits
+    }
+
+    /// The pulses of raw bytes, already in the LEDs' color order.
+    pub fn encode<'a>(&'a self, data: &'a [u8]) -> impl Iterator<Item = [Pulse; 2]> + 'a {
+        data.iter().flat_map(move |&byte| self.byte(byte))
+    }
+
+    /// The pulses of `pixels`, in the LEDs' color order. RGBW LEDs get their
+    /// white channel turned off.
+    pub fn encode_pixels<'a>(
+        &'a self,
+        pixels: &'a [RGB8],
+    ) -> impl Iterator<Item = [Pulse; 2]> + 'a {
+        pixels.iter().flat_map(move |&color| {
+            let (bytes, len) = self.color_order.arrange(color, 0);
+            (0..len).flat_map(move |i| self.byte(bytes[i]))
+        })
+    }
+}
+
+/// Converts `ns` to ticks of `clock_hz`, rounding to the nearest tick.
+fn ticks(ns: u32, clock_hz: u32) -> Result<u16, Error> {
+    let ticks = (ns as u64 * clock_hz as u64 + 500_000_000) / 1_000_000_000;
+    if ticks == 0 || ticks > MAX_TICKS as u64 {
+        return Err(Error::PulseOutOfRange { ns, clock_hz });
+    }
+    Ok(ticks as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// APB clock divided by 2, as used by the board support crate
+    const CLOCK_40MHZ: u32 = 40_000_000;
+
+    fn pulses(high: u16, low: u16) -> [Pulse; 2] {
+        [Pulse::new(true, high), Pulse::new(false, low)]
+    }
+
+    #[test]
+    fn ws2812b_pixel() {
+        let encoder = Encoder::new(LedChip::Ws2812b, CLOCK_40MHZ).unwrap();
+        let o = pulses(16, 34);
+        let l = pulses(32, 18);
+
+        let encoded = encoder
+            .encode_pixels(&[RGB8::new(0x12, 0x34, 0x56)])
+            .collect::<Vec<_>>();
+        #[rustfmt::skip]
+        let expected = vec![
+            o, o, l, l, o, l, o, o, // G 0x34
+            o, o, o, l, o, o, l, o, // R 0x12
+            o, l, o, l, o, l, l, o, // B 0x56
+        ];
+        assert_eq!(encoded, expected);
+    }
+
+    #[test]
+    fn ws2811_pixel() {
+        let encoder = Encoder::new(LedChip::Ws2811, CLOCK_40MHZ).unwrap();
+        let o = pulses(10, 40);
+        let l = pulses(24, 26);
+
+        let encoded = encoder
+            .encode_pixels(&[RGB8::new(0xff, 0x00, 0x81)])
+            .collect::<Vec<_>>();
+        #[rustfmt::skip]
+        let expected = vec![
+            l, l, l, l, l, l, l, l, // R 0xff
+            o, o, o, o, o, o, o, o, // G 0x00
+            l, o, o, o, o, o, o, l, // B 0x81
+        ];
+        assert_eq!(encoded, expected);
+    }
+
+    #[test]
+    fn sk6812_rgbw_pixel() {
+        let encoder = Encoder::new(LedChip::Sk6812Rgbw, CLOCK_40MHZ).unwrap();
+        let o = pulses(12, 36);
+        let l = pulses(24, 24);
+
+        let mut data = Vec::new();
+        encoder
+            .color_order()
+            .extend(RGB8::new(0x01, 0x80, 0x00), 0xf0, &mut data);
+        assert_eq!(data, [0x80, 0x01, 0x00, 0xf0]);
+
+        let encoded = encoder.encode(&data).collect::<Vec<_>>();
+        #[rustfmt::skip]
+        let expected = vec![
+            l, o, o, o, o, o, o, o, // G 0x80
+            o, o, o, o, o, o, o, l, // R 0x01
+            o, o, o, o, o, o, o, o, // B 0x00
+            l, l, l, l, o, o, o, o, // W 0xf0
+        ];
+        assert_eq!(encoded, expected);
+
+        // white stays off for RGB pixels
+        let pixels = [RGB8::new(0x01, 0x80, 0x00)];
+        let encoded = encoder.encode_pixels(&pixels);
+        assert_eq!(encoded.skip(24).collect::<Vec<_>>(), vec![o; 8]);
+    }
+
+    #[test]
+    fn apa106_pixel_rounds_to_nearest_tick() {
+        // 12.5ns per tick: 350ns are 28 ticks, 1360ns are 108.8 ticks
+        let encoder = Encoder::new(LedChip::Apa106, 80_000_000).unwrap();
+        assert_eq!(encoder.bit(false), pulses(28, 109));
+        assert_eq!(encoder.bit(true), pulses(109, 28));
+
+        let encoded = encoder
+            .encode_pixels(&[RGB8::new(0xaa, 0x00, 0x00)])
+            .take(8)
+            .collect::<Vec<_>>();
+        let (o, l) = (encoder.bit(false), encoder.bit(true));
+        assert_eq!(encoded, vec![l, o, l, o, l, o, l, o]);
+    }
+
+    #[test]
+    fn board_led_keeps_its_timing() {
+        let encoder = Encoder::new(LedChip::Ws2812, CLOCK_40MHZ).unwrap();
+        assert_eq!(encoder.bit(false), pulses(14, 40));
+        assert_eq!(encoder.bit(true), pulses(40, 14));
+        assert_eq!(encoder.color_order(), ColorOrder::Grb);
+        assert_eq!(encoder.latch(), Duration::from_micros(280));
+    }
+
+    #[test]
+    fn strips_are_sent_pixel_by_pixel() {
+        let encoder = Encoder::new(LedChip::Ws2812b, CLOCK_40MHZ).unwrap();
+        let pixels = [RGB8::new(1, 2, 3), RGB8::new(4, 5, 6)];
+        let encoded = encoder.encode_pixels(&pixels).collect::<Vec<_>>();
+        let expected = encoder.encode(&[2, 1, 3, 5, 4, 6]).collect::<Vec<_>>();
+        assert_eq!(encoded.len(), 2 * 3 * 8);
+        assert_eq!(encoded, expected);
+    }
+
+    #[test]
+    fn clock_too_slow() {
+        // a 250ns pulse is a quarter of a 1MHz tick
+        assert_eq!(
+            Encoder::new(LedChip::Ws2811, 1_000_000),
+            Err(Error::PulseOutOfRange {
+                ns: 250,
+                clock_hz: 1_000_000
+            })
+        );
+    }
+
+    #[test]
+    fn pulse_too_long() {
+        // 1ms are 40000 ticks, more than an RMT item holds
+        let timing = Timing {
+            t0l_ns: 1_000_000,
+            ..Timing::WS2812B
+        };
+        assert_eq!(
+            Encoder::with_timing(&timing, ColorOrder::Grb, CLOCK_40MHZ),
+            Err(Error::PulseOutOfRange {
+                ns: 1_000_000,
+                clock_hz: CLOCK_40MHZ
+            })
+        );
+    }
+}
@@ -25,44 +25,43 @@ pub struct Config {
 /// Entry point to our application.
 ///
 /// It sets up a Wi-Fi connection to the Access Point given in the
-/// configuration, then blinks the RGB LED green/blue.
+/// configuration. The RGB LED shows how that goes: it starts off yellow,
+/// blinks blue while connecting and slowly pulses blue once connected.
 ///
-/// If the LED goes solid red, then it was unable to connect to your Wi-Fi
-/// network.
+/// If the LED blinks red twice, pauses and repeats, then it was unable to
+/// connect to your Wi-Fi network. The board restarts after a few rounds and
+/// logs why the previous boot failed.
 fn main() -> anyhow::Result<()> {
-    use bsc::led::RGB8;
+    use bsc::fault;
 
     esp_idf_sys::link_patches();
 
     println!("Hello, world!");
 
+    fault::install_panic_hook();
+    if let Some(fault) = fault::take_last_fault() {
+        warn!("previous boot failed with {}", fault);
+    }
+    fault::run(run)
+}
+
+fn run() -> anyhow::Result<()> {
+    use bsc::status::{self, StatusIndicator};
+
     // Start the LED off yellow
-    let mut led = bsc::led::WS2812RMT::new()?;
-    led.set_pixel(RGB8::new(50, 50, 0))?;
+    let led = bsc::led::WS2812RMT::new()?;
+    status::install(StatusIndicator::start(led)?)?;
 
     // The constant `CONFIG` is auto-generated by `toml_config`.
     let app_config = CONFIG;
 
     // Connect to the Wi-Fi network
-    let _wifi = match bsc::wifi::wifi(app_config.wifi_ssid, app_config.wifi_psk) {
-        Ok(inner) => inner,
-        Err(err) => {
-            // Red!
-            led.set_pixel(RGB8::new(50, 0, 0))?;
-            anyhow::bail!("could not connect to Wi-Fi network: {:?}", err)
-        }
-    };
+    // (the LED blinks the Wi-Fi fault code if this fails)
+    let _wifi = bsc::wifi::wifi(app_config.wifi_ssid, app_config.wifi_psk)?;
 
     loop {
-        // Blue!
-        led.set_pixel(RGB8::new(0, 0, 50))?;
-        // Wait...
-        std::thread::sleep(std::time::Duration::from_secs(1));
+        // The LED keeps pulsing on its own thread, no need to wait for it
+        std::thread::sleep(std::time::Duration::from_secs(2));
         info!("Hello, world!");
-
-        // Green!
-        led.set_pixel(RGB8::new(0, 50, 0))?;
-        // Wait...
-        std::thread::sleep(std::time::Duration::from_secs(1));
     }
 }
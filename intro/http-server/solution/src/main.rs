@@ -1,9 +1,5 @@
 use core::str;
-use std::{
-    sync::{Arc, Mutex},
-    thread::sleep,
-    time::Duration,
-};
+use std::{thread::sleep, time::Duration};
 
 use bsc::{temp_sensor::BoardTempSensor, wifi::wifi};
 use embedded_svc::{
@@ -39,11 +35,11 @@ fn main() -> anyhow::Result<()> {
         writer.complete()
     })?;
 
-    let temp_sensor_main = Arc::new(Mutex::new(BoardTempSensor::new_taking_peripherals()));
-    let temp_sensor = temp_sensor_main.clone();
+    // can be cloned into as many handlers as needed
+    let temp_sensor = BoardTempSensor::take()?.shared();
 
     server.set_inline_handler("/temperature", Method::Get, move |request, response| {
-        let temp_val = temp_sensor.lock().unwrap().read_owning_peripherals();
+        let temp_val = temp_sensor.read();
         let html = temperature(temp_val);
         let mut writer = response.into_writer(request)?;
         writer.do_write_all(html.as_bytes())?;
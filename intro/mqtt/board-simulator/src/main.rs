@@ -0,0 +1,212 @@
+//! # Board Simulator
+//!
+//! Speaks the same MQTT protocol as `intro/mqtt/solution`, without needing an
+//! ESP32-C3: every simulated board says hello, publishes a synthetic
+//! temperature reading periodically and renders the LED colors it is sent.
+
+use mqtt_messages::{
+    cmd_topic_fragment, color_topic, hello_topic, temperature_data_topic, ColorData, Command,
+    LedScript, RawCommandData, RGB8,
+};
+use rand::Rng;
+use rumqttc::{Client, Connection, MqttOptions, Packet, Publish, QoS};
+use std::borrow::Cow;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const UUID: &str = get_uuid::uuid();
+
+#[derive(Debug)]
+#[toml_cfg::toml_config]
+pub struct Config {
+    #[default("localhost")]
+    mqtt_host: &'static str,
+    #[default("")]
+    mqtt_user: &'static str,
+    #[default("")]
+    mqtt_pass: &'static str,
+    #[default(1)]
+    boards: usize,
+    #[default(1000)]
+    publish_interval_ms: u64,
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    dbg!(CONFIG);
+
+    let boards = match std::env::args().nth(1) {
+        Some(arg) => arg.parse()?,
+        None => CONFIG.boards,
+    };
+
+    // board 0 is the one `host-client` talks to, the others are there for load testing
+    let uuids = std::iter::once(UUID.to_string())
+        .chain((1..boards).map(|_| uuid::Uuid::new_v4().to_string()))
+        .collect::<Vec<_>>();
+
+    let handles = uuids
+        .into_iter()
+        .map(|uuid| {
+            thread::spawn(move || {
+                if let Err(e) = simulate(&uuid) {
+                    println!("[{}] simulation stopped: {}", uuid, e);
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for handle in handles {
+        let _ = handle.join();
+    }
+
+    Ok(())
+}
+
+/// Runs a single simulated board until its connection fails.
+fn simulate(uuid: &str) -> Result<(), Box<dyn Error>> {
+    // the client id must not collide with `host-client`, which uses the bare UUID
+    let client_id = format!("board-{}", uuid);
+    let mut mqttoptions = MqttOptions::new(client_id, CONFIG.mqtt_host, 1883);
+    mqttoptions.set_credentials(CONFIG.mqtt_user, CONFIG.mqtt_pass);
+    mqttoptions.set_keep_alive(Duration::from_secs(5));
+
+    let (mut client, connection) = Client::new(mqttoptions, 10);
+
+    println!("[{}] board online", uuid);
+
+    let payload: &[u8] = &[];
+    client.publish(hello_topic(uuid), QoS::AtLeastOnce, true, payload)?;
+
+    client.subscribe(color_topic(uuid), QoS::AtLeastOnce)?;
+    client.subscribe(format!("{}#", cmd_topic_fragment(uuid)), QoS::AtLeastOnce)?;
+
+    let publisher_uuid = uuid.to_string();
+    thread::spawn(move || publish_temperatures(&publisher_uuid, client));
+
+    process_messages(uuid, connection)
+}
+
+/// Publishes a slowly drifting temperature, like the on-chip sensor would.
+fn publish_temperatures(uuid: &str, mut client: Client) {
+    let mut rng = rand::thread_rng();
+    let mut temp: f32 = 25. + rng.gen_range(-2.0..2.0);
+    loop {
+        thread::sleep(Duration::from_millis(CONFIG.publish_interval_ms));
+        temp = (temp + rng.gen_range(-0.25..0.25)).clamp(-10., 80.);
+        if client
+            .publish(
+                temperature_data_topic(uuid),
+                QoS::AtLeastOnce,
+                false,
+                temp.to_be_bytes().to_vec(),
+            )
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+fn process_messages(uuid: &str, mut connection: Connection) -> Result<(), Box<dyn Error>> {
+    let led = Led::new(uuid);
+    for notification in connection.iter() {
+        if let rumqttc::Event::Incoming(Packet::Publish(publish_data)) = notification? {
+            process_message(uuid, &publish_data, &led);
+        }
+    }
+    Ok(())
+}
+
+fn process_message(uuid: &str, publish_data: &Publish, led: &Led) {
+    let data: &[u8] = &publish_data.payload;
+
+    if publish_data.topic == color_topic(uuid) {
+        match ColorData::try_from(data) {
+            Ok(ColorData::BoardLed(color)) => led.set(color),
+            Err(_) => println!("[{}] invalid color data: {} bytes", uuid, data.len()),
+        }
+    } else if let Some(command_str) = publish_data.topic.split(&cmd_topic_fragment(uuid)).nth(1) {
+        let raw = RawCommandData {
+            path: command_str,
+            data: Cow::Borrowed(data),
+        };
+
+        match Command::try_from(raw) {
+            Ok(Command::BoardLed(color)) => led.set(color),
+            Ok(Command::BoardLedScript(script)) => led.play(script),
+            Err(_) => println!("[{}] invalid command `{}`", uuid, command_str),
+        }
+    }
+}
+
+/// The simulated board LED, rendered as a colored block on true color terminals.
+#[derive(Clone)]
+struct Led {
+    uuid: Arc<str>,
+    /// Incremented by every command, so a running script knows when to stop.
+    /// Held while rendering, so a script can't render after a newer command.
+    generation: Arc<Mutex<u32>>,
+}
+
+impl Led {
+    const FRAME: Duration = Duration::from_millis(50);
+
+    fn new(uuid: &str) -> Self {
+        Self {
+            uuid: uuid.into(),
+            generation: Default::default(),
+        }
+    }
+
+    fn set(&self, color: RGB8) {
+        let mut generation = self.generation.lock().unwrap();
+        *generation += 1;
+        self.render(color);
+    }
+
+    fn play(&self, script: LedScript) {
+        let generation = {
+            let mut current = self.generation.lock().unwrap();
+            *current += 1;
+            *current
+        };
+        println!(
+            "[{}] playing LED script: {} keyframes",
+            self.uuid,
+            script.keyframes.len()
+        );
+
+        let led = self.clone();
+        thread::spawn(move || {
+            let start = Instant::now();
+            let mut last_color = None;
+            loop {
+                let elapsed = start.elapsed();
+                let color = script.color_at(elapsed);
+                {
+                    let current = led.generation.lock().unwrap();
+                    if *current != generation {
+                        break;
+                    }
+                    if last_color != Some(color) {
+                        led.render(color);
+                        last_color = Some(color);
+                    }
+                }
+                if script.finished(elapsed) {
+                    break;
+                }
+                thread::sleep(Self::FRAME);
+            }
+        });
+    }
+
+    fn render(&self, color: RGB8) {
+        println!(
+            "[{}] board LED: \x1b[48;2;{};{};{}m    \x1b[0m {}",
+            self.uuid, color.r, color.g, color.b, color
+        );
+    }
+}
@@ -0,0 +1,196 @@
+//! Plays keyframe animations from a TOML or JSON file on the board LED.
+//!
+//! ```console
+//! $ cargo run -- animate show.toml [--loop] [--speed 2.0] [--upload]
+//! ```
+//!
+//! By default, the animation is streamed as one `Command::BoardLed` per
+//! frame. With `--upload`, the whole script is sent as a single
+//! `Command::BoardLedScript` and the board plays it on its own.
+//!
+//! A script file looks like this (all keys but `color` and `duration_ms` are optional):
+//!
+//! ```toml
+//! repeat = true
+//!
+//! [[keyframes]]
+//! color = [255, 0, 0]
+//! duration_ms = 500
+//! easing = "ease-in-out" # step, linear (default), ease-in, ease-out or ease-in-out
+//! ```
+
+use mqtt_messages::{Command, Easing, Keyframe, LedScript, RGB8};
+use rumqttc::{Connection, QoS};
+use serde::Deserialize;
+use std::error::Error;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::session::Session;
+use crate::{CONFIG, UUID};
+
+#[derive(Deserialize)]
+struct ScriptFile {
+    #[serde(default)]
+    repeat: bool,
+    keyframes: Vec<KeyframeEntry>,
+}
+
+#[derive(Deserialize)]
+struct KeyframeEntry {
+    color: [u8; 3],
+    duration_ms: u32,
+    easing: Option<String>,
+}
+
+struct Options {
+    path: String,
+    repeat: bool,
+    speed: f32,
+    upload: bool,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Result<Self, Box<dyn Error>> {
+        let mut path = None;
+        let mut options = Options {
+            path: String::new(),
+            repeat: false,
+            speed: 1.,
+            upload: false,
+        };
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--loop" => options.repeat = true,
+                "--upload" => options.upload = true,
+                "--speed" => {
+                    let speed = args.next().ok_or("`--speed` needs a value")?;
+                    options.speed = speed.parse()?;
+                    if options.speed <= 0. {
+                        return Err("`--speed` must be positive".into());
+                    }
+                }
+                _ if path.is_none() => path = Some(arg.clone()),
+                _ => return Err(format!("unexpected argument `{}`", arg).into()),
+            }
+        }
+
+        options.path =
+            path.ok_or("usage: animate <script file> [--loop] [--speed <factor>] [--upload]")?;
+        Ok(options)
+    }
+}
+
+pub fn run(
+    session: Session,
+    mut connection: Connection,
+    args: &[String],
+) -> Result<(), Box<dyn Error>> {
+    let options = Options::parse(args)?;
+    let script = load(&options)?;
+
+    println!(
+        "playing {} keyframes ({:?}{}) on board {}",
+        script.keyframes.len(),
+        script.duration(),
+        if script.repeat { ", repeating" } else { "" },
+        UUID
+    );
+
+    if options.upload && script.keyframes.len() > LedScript::MAX_KEYFRAMES {
+        return Err(format!(
+            "the board can't take more than {} keyframes at once",
+            LedScript::MAX_KEYFRAMES
+        )
+        .into());
+    }
+
+    let player_session = session.clone();
+    let player = thread::spawn(move || {
+        if options.upload {
+            upload(player_session, script)
+        } else {
+            stream(player_session, script)
+        }
+    });
+
+    session.run(&mut connection, |_| {});
+    let _ = player.join();
+    Ok(())
+}
+
+/// Reads a script file, applying the `--loop` and `--speed` options.
+fn load(options: &Options) -> Result<LedScript, Box<dyn Error>> {
+    let content = std::fs::read_to_string(&options.path)
+        .map_err(|e| format!("could not read {}: {}", options.path, e))?;
+    let file: ScriptFile = match Path::new(&options.path)
+        .extension()
+        .and_then(|extension| extension.to_str())
+    {
+        Some("json") => serde_json::from_str(&content)?,
+        Some("toml") => toml::from_str(&content)?,
+        _ => return Err("script files must end in `.toml` or `.json`".into()),
+    };
+
+    if file.keyframes.is_empty() {
+        return Err("the script has no keyframes".into());
+    }
+
+    let keyframes = file
+        .keyframes
+        .into_iter()
+        .map(|entry| {
+            let easing = match entry.easing.as_deref() {
+                Some(name) => name
+                    .parse()
+                    .map_err(|_| format!("unknown easing `{}`", name))?,
+                None => Easing::Linear,
+            };
+            let duration_ms = (entry.duration_ms as f32 / options.speed).round();
+            if duration_ms > u16::MAX as f32 {
+                return Err(format!("keyframes can't be longer than {}ms", u16::MAX));
+            }
+            Ok(Keyframe {
+                color: RGB8::from(entry.color),
+                duration_ms: duration_ms as u16,
+                easing,
+            })
+        })
+        .collect::<Result<_, _>>()?;
+
+    Ok(LedScript {
+        keyframes,
+        repeat: file.repeat || options.repeat,
+    })
+}
+
+/// Sends a `Command::BoardLed` whenever the color changes, at most once per frame.
+fn stream(mut session: Session, script: LedScript) {
+    let frame = Duration::from_millis(CONFIG.animation_frame_ms);
+    let start = Instant::now();
+    let mut last_color = None;
+    loop {
+        let elapsed = start.elapsed();
+        let color = script.color_at(elapsed);
+        if last_color != Some(color) {
+            let command = Command::BoardLed(color);
+            session.publish(command.topic(UUID), QoS::AtMostOnce, false, command.data());
+            last_color = Some(color);
+        }
+        if script.finished(elapsed) {
+            break;
+        }
+        thread::sleep(frame);
+    }
+    session.disconnect();
+}
+
+fn upload(mut session: Session, script: LedScript) {
+    let command = Command::BoardLedScript(script);
+    session.publish(command.topic(UUID), QoS::AtLeastOnce, false, command.data());
+    println!("script uploaded");
+    session.disconnect();
+}
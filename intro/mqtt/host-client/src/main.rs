@@ -1,11 +1,19 @@
-use mqtt_messages::{hello_topic, temperature_data_topic, ColorData, Command, RGB8};
+use mqtt_messages::{hello_topic, temperature_data_topic, ColorData, RGB8};
 use rand::Rng;
-use rumqttc::{Client, MqttOptions, Packet, QoS};
+use rumqttc::{Client, Connection, MqttOptions, QoS};
+use session::Session;
 use std::error::Error;
 use std::thread;
 use std::time::Duration;
 
-const UUID: &'static str = get_uuid::uuid();
+mod animation;
+mod rules;
+mod session;
+mod store;
+mod stress;
+mod tls;
+
+const UUID: &str = get_uuid::uuid();
 
 #[derive(Debug)]
 #[toml_cfg::toml_config]
@@ -16,21 +24,81 @@ pub struct Config {
     mqtt_user: &'static str,
     #[default("")]
     mqtt_pass: &'static str,
+    #[default(1883)]
+    mqtt_port: u16,
+    #[default(false)]
+    mqtt_tls: bool,
+    #[default("")]
+    mqtt_tls_ca: &'static str,
+    #[default("")]
+    mqtt_tls_client_cert: &'static str,
+    #[default("")]
+    mqtt_tls_client_key: &'static str,
+    #[default(true)]
+    mqtt_tls_sni: bool,
+    #[default("")]
+    mqtt_tls_alpn: &'static str,
+    #[default(500)]
+    reconnect_min_ms: u64,
+    #[default(30000)]
+    reconnect_max_ms: u64,
+    #[default(100)]
+    offline_queue_size: usize,
+    #[default(20)]
+    stress_rate_hz: u32,
+    #[default(6000)]
+    stress_payload_size: usize,
+    #[default(10)]
+    stress_duration_s: u64,
+    #[default(5)]
+    stress_liveness_timeout_s: u64,
+    #[default(50)]
+    animation_frame_ms: u64,
+    #[default("host-client.sqlite")]
+    database: &'static str,
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
+    let args = std::env::args().collect::<Vec<_>>();
+    // doesn't need the broker
+    if args.get(1).map(String::as_str) == Some("query") {
+        return store::query(&args[2..]);
+    }
+
     dbg!(CONFIG);
     let client_id = UUID;
     dbg!(UUID);
-    let mut mqttoptions = MqttOptions::new(client_id, CONFIG.mqtt_host, 1883);
+    let mut mqttoptions = MqttOptions::new(client_id, CONFIG.mqtt_host, CONFIG.mqtt_port);
     mqttoptions.set_credentials(CONFIG.mqtt_user, CONFIG.mqtt_pass);
+    if CONFIG.mqtt_tls {
+        mqttoptions.set_transport(tls::transport()?);
+    }
 
     mqttoptions.set_keep_alive(Duration::from_secs(5));
 
-    let (mut client, mut connection) = Client::new(mqttoptions, 10);
+    let (client, connection) = Client::new(mqttoptions, 10);
+    let session = Session::new(client);
 
-    client.subscribe(temperature_data_topic(UUID), QoS::AtMostOnce)?;
-    client.subscribe(hello_topic(UUID), QoS::AtMostOnce)?;
+    match args.get(1).map(String::as_str) {
+        None => random_colors(session, connection),
+        Some("stress") => stress::run(session, connection),
+        Some("animate") => animation::run(session, connection, &args[2..]),
+        Some("rules") => rules::run(session, connection, &args[2..]),
+        Some("record") => store::record(session, connection),
+        Some(command) => Err(format!(
+            "unknown command `{}`, expected `stress`, `animate`, `rules`, `record` or `query`",
+            command
+        )
+        .into()),
+    }
+}
+
+/// Sends a random color to the board every second and prints what it reports.
+fn random_colors(mut session: Session, mut connection: Connection) -> Result<(), Box<dyn Error>> {
+    session.subscribe(temperature_data_topic(UUID), QoS::AtMostOnce);
+    session.subscribe(hello_topic(UUID), QoS::AtMostOnce);
+
+    let mut publisher = session.clone();
     thread::spawn(move || {
         let mut rng = rand::thread_rng();
         loop {
@@ -40,41 +108,37 @@ fn main() -> Result<(), Box<dyn Error>> {
             let color = RGB8::new(r, g, b);
             println!("setting new color: {}", color);
             let color = ColorData::BoardLed(color);
-            //let command = Command::BoardLed(color);
-            client
-                .publish(
-                    color.topic(UUID),
-                    //command.topic(UUID),
-                    QoS::AtLeastOnce,
-                    false,
-                    color.data(),
-                    //command.data().clone(),
-                )
-                .unwrap();
+            //let command = mqtt_messages::Command::BoardLed(color);
+            publisher.publish(
+                color.topic(UUID),
+                //command.topic(UUID),
+                QoS::AtLeastOnce,
+                false,
+                color.data(),
+                //command.data().clone(),
+            );
             thread::sleep(Duration::from_secs(1));
         }
     });
 
-    // Iterate to poll the eventloop for connection progress
-    for (_, notification) in connection.iter().enumerate() {
+    // Poll the eventloop for connection progress, reconnecting if necessary
+    session.run(&mut connection, |publish_data| {
         // if you want to see *everything*, uncomment:
-        // println!("Notification = {:#?}", notification);
+        // println!("Publish = {:#?}", publish_data);
 
-        if let Ok(rumqttc::Event::Incoming(Packet::Publish(publish_data))) = notification {
-            if publish_data.topic == hello_topic(UUID) {
-                println!("board says hi!");
-            }
+        if publish_data.topic == hello_topic(UUID) {
+            println!("board says hi!");
+        }
 
-            if publish_data.topic == temperature_data_topic(UUID) {
-                let data: &[u8] = &publish_data.payload;
-                let data: Result<[u8; 4], _> = data.try_into();
+        if publish_data.topic == temperature_data_topic(UUID) {
+            let data: &[u8] = &publish_data.payload;
+            let data: Result<[u8; 4], _> = data.try_into();
 
-                if let Ok(data) = data {
-                    let temp: f32 = f32::from_be_bytes(data);
-                    println!("board temperature: {:.2}°C", temp)
-                }
+            if let Ok(data) = data {
+                let temp: f32 = f32::from_be_bytes(data);
+                println!("board temperature: {:.2}°C", temp)
             }
         }
-    }
+    });
     Ok(())
 }
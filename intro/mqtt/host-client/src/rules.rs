@@ -0,0 +1,279 @@
+//! Rules engine: reacts to sensor readings of the board.
+//!
+//! ```console
+//! $ cargo run -- rules rules.toml
+//! ```
+//!
+//! A rule watches one sensor and triggers when its value goes above (or
+//! below) a threshold. It clears once the value is back on the other side of
+//! the threshold by more than `hysteresis`, so a noisy reading around the
+//! threshold doesn't make it flap. Both transitions can set the board LED
+//! and log an alert, where `{value}` is replaced by the reading:
+//!
+//! ```toml
+//! [[rules]]
+//! name = "overheating"
+//! sensor = "temperature"
+//! above = 35.0
+//! hysteresis = 1.0
+//! on_trigger = { board_led = [255, 0, 0], alert = "board is overheating: {value}°C" }
+//! on_clear = { board_led = [0, 50, 0], alert = "board cooled down to {value}°C" }
+//! ```
+
+use mqtt_messages::{hello_topic, temperature_data_topic, Command, SensorReading, RGB8};
+use rumqttc::{Connection, QoS};
+use serde::Deserialize;
+use std::error::Error;
+
+use crate::session::Session;
+use crate::UUID;
+
+#[derive(Deserialize)]
+struct RulesFile {
+    rules: Vec<Rule>,
+}
+
+#[derive(Deserialize)]
+struct Rule {
+    name: String,
+    sensor: String,
+    above: Option<f32>,
+    below: Option<f32>,
+    #[serde(default)]
+    hysteresis: f32,
+    #[serde(default)]
+    on_trigger: Action,
+    #[serde(default)]
+    on_clear: Action,
+    #[serde(skip)]
+    triggered: bool,
+}
+
+#[derive(Deserialize, Default)]
+struct Action {
+    board_led: Option<[u8; 3]>,
+    alert: Option<String>,
+}
+
+/// What a reading means for a rule.
+#[derive(Debug, PartialEq)]
+enum Transition {
+    Trigger,
+    Clear,
+}
+
+impl Rule {
+    fn validate(&self) -> Result<(), String> {
+        if self.sensor != "temperature" {
+            return Err(format!(
+                "rule `{}`: unknown sensor `{}`, expected `temperature`",
+                self.name, self.sensor
+            ));
+        }
+        if self.above.is_some() == self.below.is_some() {
+            return Err(format!(
+                "rule `{}` needs exactly one of `above` and `below`",
+                self.name
+            ));
+        }
+        if self.hysteresis < 0. {
+            return Err(format!(
+                "rule `{}`: hysteresis can't be negative",
+                self.name
+            ));
+        }
+        Ok(())
+    }
+
+    fn update(&mut self, reading: &SensorReading) -> Option<Transition> {
+        if reading.name() != self.sensor {
+            return None;
+        }
+        let value = reading.value();
+
+        let (trigger, clear) = match (self.above, self.below) {
+            (Some(threshold), _) => (value > threshold, value < threshold - self.hysteresis),
+            (_, Some(threshold)) => (value < threshold, value > threshold + self.hysteresis),
+            (None, None) => return None,
+        };
+
+        if !self.triggered && trigger {
+            self.triggered = true;
+            Some(Transition::Trigger)
+        } else if self.triggered && clear {
+            self.triggered = false;
+            Some(Transition::Clear)
+        } else {
+            None
+        }
+    }
+}
+
+impl Action {
+    fn run(&self, session: &mut Session, rule: &str, reading: &SensorReading) {
+        if let Some(alert) = &self.alert {
+            let value = format!("{:.1}", reading.value());
+            println!("[{}] {}", rule, alert.replace("{value}", &value));
+        }
+        if let Some(color) = self.board_led {
+            let command = Command::BoardLed(RGB8::from(color));
+            session.publish(command.topic(UUID), QoS::AtLeastOnce, false, command.data());
+        }
+    }
+}
+
+pub fn run(
+    mut session: Session,
+    mut connection: Connection,
+    args: &[String],
+) -> Result<(), Box<dyn Error>> {
+    let path = match args {
+        [path] => path,
+        _ => return Err("usage: rules <rules file>".into()),
+    };
+    let content =
+        std::fs::read_to_string(path).map_err(|e| format!("could not read {}: {}", path, e))?;
+    let mut rules = toml::from_str::<RulesFile>(&content)?.rules;
+    for rule in &rules {
+        rule.validate()?;
+    }
+    println!("watching board {} with {} rules", UUID, rules.len());
+
+    session.subscribe(hello_topic(UUID), QoS::AtMostOnce);
+    session.subscribe(temperature_data_topic(UUID), QoS::AtMostOnce);
+
+    let mut action_session = session.clone();
+    session.run(&mut connection, |publish_data| {
+        if publish_data.topic == hello_topic(UUID) {
+            println!("board says hi!");
+            return;
+        }
+
+        let reading = match SensorReading::decode(UUID, &publish_data.topic, &publish_data.payload)
+        {
+            Ok(reading) => reading,
+            Err(_) => return,
+        };
+
+        for rule in &mut rules {
+            let action = match rule.update(&reading) {
+                Some(Transition::Trigger) => &rule.on_trigger,
+                Some(Transition::Clear) => &rule.on_clear,
+                None => continue,
+            };
+            action.run(&mut action_session, &rule.name, &reading);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(config: &str) -> Rule {
+        let file = format!(
+            "[[rules]]\nname = \"test\"\nsensor = \"temperature\"\n{}",
+            config
+        );
+        let mut rules = toml::from_str::<RulesFile>(&file).unwrap().rules;
+        let rule = rules.remove(0);
+        rule.validate().unwrap();
+        rule
+    }
+
+    fn updates(rule: &mut Rule, values: &[f32]) -> Vec<Option<Transition>> {
+        values
+            .iter()
+            .map(|&value| rule.update(&SensorReading::Temperature(value)))
+            .collect()
+    }
+
+    #[test]
+    fn rising_edge() {
+        let mut rule = rule("above = 35.0\nhysteresis = 1.0");
+        assert_eq!(
+            updates(&mut rule, &[30., 35., 35.1, 40.]),
+            [None, None, Some(Transition::Trigger), None]
+        );
+    }
+
+    #[test]
+    fn clears_below_the_band() {
+        let mut rule = rule("above = 35.0\nhysteresis = 1.0");
+        assert_eq!(
+            updates(&mut rule, &[36., 34.5, 34., 33.9, 33.]),
+            [
+                Some(Transition::Trigger),
+                None,
+                None,
+                Some(Transition::Clear),
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn no_retrigger_inside_the_band() {
+        let mut rule = rule("above = 35.0\nhysteresis = 1.0");
+        assert_eq!(
+            updates(&mut rule, &[36., 34.5, 35.5, 34.2, 36.]),
+            [Some(Transition::Trigger), None, None, None, None]
+        );
+        // only once it has cleared
+        assert_eq!(
+            updates(&mut rule, &[33., 35.5]),
+            [Some(Transition::Clear), Some(Transition::Trigger)]
+        );
+    }
+
+    #[test]
+    fn falling_edge() {
+        let mut rule = rule("below = 10.0\nhysteresis = 2.0");
+        assert_eq!(
+            updates(&mut rule, &[15., 9.9, 11., 11.9, 12.1, 9.]),
+            [
+                None,
+                Some(Transition::Trigger),
+                None,
+                None,
+                Some(Transition::Clear),
+                Some(Transition::Trigger)
+            ]
+        );
+    }
+
+    #[test]
+    fn without_hysteresis() {
+        let mut rule = rule("above = 20.0");
+        assert_eq!(
+            updates(&mut rule, &[21., 20., 19.9, 20.1]),
+            [
+                Some(Transition::Trigger),
+                None,
+                Some(Transition::Clear),
+                Some(Transition::Trigger)
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_rules() {
+        let parse = |config: &str| {
+            let file = format!("[[rules]]\nname = \"test\"\n{}", config);
+            toml::from_str::<RulesFile>(&file).unwrap().rules.remove(0)
+        };
+        assert!(parse("sensor = \"humidity\"\nabove = 1.0")
+            .validate()
+            .is_err());
+        assert!(parse("sensor = \"temperature\"").validate().is_err());
+        assert!(parse("sensor = \"temperature\"\nabove = 1.0\nbelow = 0.0")
+            .validate()
+            .is_err());
+        assert!(
+            parse("sensor = \"temperature\"\nabove = 1.0\nhysteresis = -1.0")
+                .validate()
+                .is_err()
+        );
+    }
+}
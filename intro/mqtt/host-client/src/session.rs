@@ -0,0 +1,413 @@
+//! A connection to the broker that survives broker restarts.
+//!
+//! `rumqttc` reconnects whenever the event loop is polled again after an
+//! error, but it neither waits between attempts nor restores subscriptions
+//! of a clean session. `Session` adds both, and buffers publishes in a
+//! bounded queue while the broker is unreachable.
+
+use rumqttc::{Client, Connection, Event, Outgoing, Packet, Publish, QoS};
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::CONFIG;
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Metrics {
+    /// Messages handed to the event loop.
+    pub published: u64,
+    /// Messages that had to wait in the offline queue.
+    pub queued: u64,
+    /// Messages that were dropped because the offline queue was full.
+    pub dropped: u64,
+    /// Successful connections after the first one.
+    pub reconnects: u64,
+}
+
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "published: {}, queued: {}, dropped: {}, reconnects: {}",
+            self.published, self.queued, self.dropped, self.reconnects
+        )
+    }
+}
+
+struct OutgoingMessage {
+    topic: String,
+    qos: QoS,
+    retain: bool,
+    payload: Vec<u8>,
+}
+
+/// Pause between reconnection attempts: doubles with every failed one, up to `max`.
+struct Backoff {
+    min: Duration,
+    max: Duration,
+    next: Duration,
+}
+
+impl Backoff {
+    fn new(min: Duration, max: Duration) -> Self {
+        Self {
+            min,
+            max,
+            next: min,
+        }
+    }
+
+    /// The pause before the next attempt
+    fn next(&mut self) -> Duration {
+        let pause = self.next;
+        self.next = (self.next * 2).min(self.max);
+        pause
+    }
+
+    /// After a successful connection
+    fn reset(&mut self) {
+        self.next = self.min;
+    }
+}
+
+/// Everything but the client: the offline queue, subscriptions and metrics.
+/// Messages are handed on with a `send` function, which gives them back if
+/// the event loop can't take them right now.
+struct State {
+    online: bool,
+    connected_once: bool,
+    disconnecting: bool,
+    subscriptions: Vec<(String, QoS)>,
+    queue: VecDeque<OutgoingMessage>,
+    queue_size: usize,
+    metrics: Metrics,
+}
+
+impl State {
+    fn new(queue_size: usize) -> Self {
+        Self {
+            online: false,
+            connected_once: false,
+            disconnecting: false,
+            subscriptions: Vec::new(),
+            queue: VecDeque::new(),
+            queue_size,
+            metrics: Metrics::default(),
+        }
+    }
+
+    /// Sends `message`, or queues it if the broker is unreachable or older
+    /// messages are still waiting.
+    fn publish(
+        &mut self,
+        message: OutgoingMessage,
+        send: &mut impl FnMut(OutgoingMessage) -> Result<(), OutgoingMessage>,
+    ) {
+        if self.online {
+            self.flush(send);
+        }
+        // keep the order of messages: only skip the queue if it is empty
+        if !self.online || !self.queue.is_empty() {
+            self.enqueue(message);
+            return;
+        }
+        match send(message) {
+            Ok(()) => self.metrics.published += 1,
+            Err(message) => self.enqueue(message),
+        }
+    }
+
+    /// Sends queued messages, in order, for as long as `send` takes them.
+    /// Returns whether the queue is empty.
+    fn flush(
+        &mut self,
+        send: &mut impl FnMut(OutgoingMessage) -> Result<(), OutgoingMessage>,
+    ) -> bool {
+        while let Some(message) = self.queue.pop_front() {
+            match send(message) {
+                Ok(()) => self.metrics.published += 1,
+                Err(message) => {
+                    self.queue.push_front(message);
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// Queues `message`, dropping the oldest ones if the queue is full.
+    fn enqueue(&mut self, message: OutgoingMessage) {
+        self.queue.push_back(message);
+        self.metrics.queued += 1;
+        while self.queue.len() > self.queue_size {
+            self.queue.pop_front();
+            self.metrics.dropped += 1;
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Session {
+    client: Client,
+    state: Arc<Mutex<State>>,
+}
+
+impl Session {
+    pub fn new(client: Client) -> Self {
+        Self {
+            client,
+            state: Arc::new(Mutex::new(State::new(CONFIG.offline_queue_size))),
+        }
+    }
+
+    /// Subscribes to `topic` now and after every reconnect.
+    pub fn subscribe(&mut self, topic: impl Into<String>, qos: QoS) {
+        let topic = topic.into();
+        let mut state = self.state.lock().unwrap();
+        if state.online {
+            // if this fails, the connection is going down and we'll resubscribe anyway
+            let _ = self.client.try_subscribe(topic.clone(), qos);
+        }
+        state.subscriptions.push((topic, qos));
+    }
+
+    /// Publishes `payload`, or queues it if the broker is unreachable.
+    ///
+    /// If the queue is full, the oldest queued message is dropped.
+    pub fn publish(
+        &mut self,
+        topic: impl Into<String>,
+        qos: QoS,
+        retain: bool,
+        payload: impl Into<Vec<u8>>,
+    ) {
+        let message = OutgoingMessage {
+            topic: topic.into(),
+            qos,
+            retain,
+            payload: payload.into(),
+        };
+
+        let mut state = self.state.lock().unwrap();
+        state.publish(message, &mut |message| self.try_publish(message));
+    }
+
+    pub fn metrics(&self) -> Metrics {
+        self.state.lock().unwrap().metrics
+    }
+
+    /// Makes `run` return, without waiting for queued messages to be sent.
+    pub fn stop(&self) {
+        let _ = self.client.clone().cancel();
+    }
+
+    /// Makes `run` return once everything handed to the event loop so far has been sent.
+    pub fn disconnect(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.disconnecting = true;
+        if state.online {
+            self.flush(&mut state);
+        }
+    }
+
+    /// Drives the connection, reconnecting with exponential backoff.
+    ///
+    /// Every incoming publish is passed to `on_publish`. Only returns once the
+    /// client has been dropped or disconnected.
+    pub fn run(&self, connection: &mut Connection, mut on_publish: impl FnMut(&Publish)) {
+        let mut backoff = Backoff::new(
+            Duration::from_millis(CONFIG.reconnect_min_ms),
+            Duration::from_millis(CONFIG.reconnect_max_ms),
+        );
+
+        for notification in connection.iter() {
+            match notification {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    backoff.reset();
+                    self.connected();
+                }
+                Ok(Event::Incoming(Packet::Publish(publish_data))) => on_publish(&publish_data),
+                Ok(Event::Outgoing(Outgoing::Disconnect)) => return,
+                // the event loop took a request, so there is room for queued messages
+                Ok(Event::Outgoing(_)) => {
+                    let mut state = self.state.lock().unwrap();
+                    if state.online {
+                        self.flush(&mut state);
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    let metrics = {
+                        let mut state = self.state.lock().unwrap();
+                        state.online = false;
+                        state.metrics
+                    };
+                    let pause = backoff.next();
+                    println!(
+                        "connection error: {}, retrying in {:?} ({})",
+                        e, pause, metrics
+                    );
+                    thread::sleep(pause);
+                }
+            }
+        }
+    }
+
+    fn connected(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.connected_once {
+            state.metrics.reconnects += 1;
+            println!("reconnected ({})", state.metrics);
+        }
+        state.connected_once = true;
+        state.online = true;
+
+        // the session is clean, so the broker forgot about our subscriptions
+        let mut client = self.client.clone();
+        for (topic, qos) in &state.subscriptions {
+            let _ = client.try_subscribe(topic.clone(), *qos);
+        }
+        self.flush(&mut state);
+    }
+
+    /// Hands queued messages to the event loop. Once they are all out,
+    /// disconnects if `disconnect` has been called.
+    fn flush(&self, state: &mut State) {
+        if state.flush(&mut |message| self.try_publish(message)) && state.disconnecting {
+            let _ = self.client.clone().try_disconnect();
+        }
+    }
+
+    fn try_publish(&self, message: OutgoingMessage) -> Result<(), OutgoingMessage> {
+        let mut client = self.client.clone();
+        client
+            .try_publish(
+                message.topic.as_str(),
+                message.qos,
+                message.retain,
+                message.payload.as_slice(),
+            )
+            .map_err(|_| message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(topic: &str) -> OutgoingMessage {
+        OutgoingMessage {
+            topic: topic.into(),
+            qos: QoS::AtMostOnce,
+            retain: false,
+            payload: Vec::new(),
+        }
+    }
+
+    fn topics(queue: &VecDeque<OutgoingMessage>) -> Vec<&str> {
+        queue.iter().map(|message| message.topic.as_str()).collect()
+    }
+
+    /// Takes `capacity` messages, then refuses until it's given more
+    struct EventLoop {
+        capacity: usize,
+        sent: Vec<String>,
+    }
+
+    impl EventLoop {
+        fn new(capacity: usize) -> Self {
+            Self {
+                capacity,
+                sent: Vec::new(),
+            }
+        }
+
+        fn send(&mut self, message: OutgoingMessage) -> Result<(), OutgoingMessage> {
+            if self.capacity == 0 {
+                return Err(message);
+            }
+            self.capacity -= 1;
+            self.sent.push(message.topic);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let mut backoff = Backoff::new(Duration::from_millis(500), Duration::from_secs(3));
+        let pauses: Vec<_> = (0..5).map(|_| backoff.next().as_millis()).collect();
+        assert_eq!(pauses, [500, 1000, 2000, 3000, 3000]);
+
+        backoff.reset();
+        assert_eq!(backoff.next(), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn sends_right_away_when_online() {
+        let mut state = State::new(10);
+        state.online = true;
+        let mut event_loop = EventLoop::new(10);
+        state.publish(message("a"), &mut |m| event_loop.send(m));
+        assert_eq!(event_loop.sent, ["a"]);
+        assert!(state.queue.is_empty());
+        assert_eq!(state.metrics.published, 1);
+        assert_eq!(state.metrics.queued, 0);
+    }
+
+    #[test]
+    fn queues_while_offline_and_drops_oldest() {
+        let mut state = State::new(2);
+        let mut event_loop = EventLoop::new(10);
+        for topic in ["a", "b", "c", "d"] {
+            state.publish(message(topic), &mut |m| event_loop.send(m));
+        }
+        assert!(event_loop.sent.is_empty());
+        assert_eq!(topics(&state.queue), ["c", "d"]);
+        assert_eq!(state.metrics.queued, 4);
+        assert_eq!(state.metrics.dropped, 2);
+
+        state.online = true;
+        assert!(state.flush(&mut |m| event_loop.send(m)));
+        assert_eq!(event_loop.sent, ["c", "d"]);
+        assert_eq!(state.metrics.published, 2);
+    }
+
+    #[test]
+    fn keeps_order_behind_queue() {
+        let mut state = State::new(10);
+        state.online = true;
+        let mut event_loop = EventLoop::new(1);
+        for topic in ["a", "b", "c"] {
+            state.publish(message(topic), &mut |m| event_loop.send(m));
+        }
+        // the event loop is full, so "c" waits behind "b"
+        assert_eq!(event_loop.sent, ["a"]);
+        assert_eq!(topics(&state.queue), ["b", "c"]);
+
+        event_loop.capacity = 1;
+        state.publish(message("d"), &mut |m| event_loop.send(m));
+        assert_eq!(event_loop.sent, ["a", "b"]);
+        assert_eq!(topics(&state.queue), ["c", "d"]);
+    }
+
+    #[test]
+    fn flush_resumes_where_it_stopped() {
+        let mut state = State::new(10);
+        let mut event_loop = EventLoop::new(1);
+        for topic in ["a", "b", "c"] {
+            state.publish(message(topic), &mut |m| event_loop.send(m));
+        }
+        state.online = true;
+        assert!(!state.flush(&mut |m| event_loop.send(m)));
+        assert_eq!(event_loop.sent, ["a"]);
+
+        // e.g. once the event loop reports progress, without another publish
+        event_loop.capacity = 10;
+        assert!(state.flush(&mut |m| event_loop.send(m)));
+        assert_eq!(event_loop.sent, ["a", "b", "c"]);
+        assert_eq!(state.metrics.published, 3);
+        assert_eq!(state.metrics.dropped, 0);
+    }
+}
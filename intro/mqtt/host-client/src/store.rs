@@ -0,0 +1,230 @@
+//! Local history of everything the boards report, kept in a SQLite database.
+//!
+//! The `record` command writes every decoded sensor reading and every hello
+//! of all boards on the broker to the `database` file from the configuration:
+//!
+//! ```console
+//! $ cargo run -- record
+//! ```
+//!
+//! The `query` command summarizes the readings per board and time window:
+//!
+//! ```console
+//! $ cargo run -- query [--window 15m] [--since 1d] [--device <uuid>]
+//! ```
+
+use mqtt_messages::SensorReading;
+use rumqttc::{Connection, Publish, QoS};
+use rusqlite::{params, Connection as Database};
+use std::error::Error;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::session::Session;
+use crate::CONFIG;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS readings (
+        uuid TEXT NOT NULL,
+        topic TEXT NOT NULL,
+        sensor TEXT NOT NULL,
+        value REAL NOT NULL,
+        timestamp_ms INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS readings_by_device ON readings (uuid, sensor, timestamp_ms);
+    CREATE TABLE IF NOT EXISTS announcements (
+        uuid TEXT NOT NULL,
+        topic TEXT NOT NULL,
+        timestamp_ms INTEGER NOT NULL
+    );
+";
+
+pub struct Store {
+    db: Database,
+}
+
+impl Store {
+    /// Opens the configured database, or returns `None` if `database` is empty.
+    pub fn open() -> Result<Option<Self>, Box<dyn Error>> {
+        if CONFIG.database.is_empty() {
+            return Ok(None);
+        }
+        let db = Database::open(CONFIG.database)
+            .map_err(|e| format!("could not open {}: {}", CONFIG.database, e))?;
+        db.execute_batch(SCHEMA)?;
+        Ok(Some(Self { db }))
+    }
+
+    /// Saves `publish_data` if it is a hello or a sensor reading of any board.
+    pub fn record(&self, publish_data: &Publish) -> rusqlite::Result<()> {
+        let topic = publish_data.topic.as_str();
+        let uuid = match topic.split_once('/') {
+            Some((uuid, _)) => uuid,
+            None => return Ok(()),
+        };
+
+        if topic == mqtt_messages::hello_topic(uuid) {
+            self.db.execute(
+                "INSERT INTO announcements (uuid, topic, timestamp_ms) VALUES (?1, ?2, ?3)",
+                params![uuid, topic, now_ms()],
+            )?;
+        } else if let Ok(reading) = SensorReading::decode(uuid, topic, &publish_data.payload) {
+            self.db.execute(
+                "INSERT INTO readings (uuid, topic, sensor, value, timestamp_ms)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![uuid, topic, reading.name(), reading.value(), now_ms()],
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Records the hellos and readings of all boards on the broker until interrupted.
+pub fn record(mut session: Session, mut connection: Connection) -> Result<(), Box<dyn Error>> {
+    let store = Store::open()?.ok_or("`record` needs a `database` in cfg.toml")?;
+    session.subscribe(mqtt_messages::hello_topic("+"), QoS::AtMostOnce);
+    session.subscribe(mqtt_messages::temperature_data_topic("+"), QoS::AtMostOnce);
+
+    println!("recording all boards to {}", CONFIG.database);
+    session.run(&mut connection, |publish_data| {
+        println!(
+            "{}: {} bytes",
+            publish_data.topic,
+            publish_data.payload.len()
+        );
+        if let Err(e) = store.record(publish_data) {
+            println!("could not save {}: {}", publish_data.topic, e);
+        }
+    });
+    Ok(())
+}
+
+struct QueryOptions {
+    window_ms: i64,
+    since_ms: Option<i64>,
+    device: Option<String>,
+}
+
+impl QueryOptions {
+    fn parse(args: &[String]) -> Result<Self, Box<dyn Error>> {
+        let mut options = QueryOptions {
+            window_ms: 60 * 60 * 1000,
+            since_ms: None,
+            device: None,
+        };
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| format!("`{}` needs a value", arg))
+            };
+            match arg.as_str() {
+                "--window" => options.window_ms = parse_duration_ms(value()?)?,
+                "--since" => options.since_ms = Some(parse_duration_ms(value()?)?),
+                "--device" => options.device = Some(value()?.clone()),
+                _ => {
+                    return Err(format!(
+                        "unexpected argument `{}`\n\
+                         usage: query [--window <duration>] [--since <duration>] [--device <uuid>]",
+                        arg
+                    )
+                    .into())
+                }
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// Prints min/max/avg of every sensor per board and time window.
+pub fn query(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let options = QueryOptions::parse(args)?;
+    let store = Store::open()?.ok_or("`query` needs a `database` in cfg.toml")?;
+
+    let since = options.since_ms.map(|since| now_ms() - since).unwrap_or(0);
+    let mut statement = store.db.prepare(
+        "SELECT uuid, sensor,
+                datetime(timestamp_ms / ?1 * ?1 / 1000, 'unixepoch') AS window_start,
+                COUNT(*), MIN(value), MAX(value), AVG(value)
+         FROM readings
+         WHERE timestamp_ms >= ?2 AND (?3 IS NULL OR uuid = ?3)
+         GROUP BY uuid, sensor, timestamp_ms / ?1
+         ORDER BY uuid, sensor, window_start",
+    )?;
+    let mut rows = statement.query(params![options.window_ms, since, options.device])?;
+
+    let mut last_series = None;
+    while let Some(row) = rows.next()? {
+        let series: (String, String) = (row.get(0)?, row.get(1)?);
+        if last_series.as_ref() != Some(&series) {
+            println!("{} {}", series.0, series.1);
+            println!(
+                "  {:<19} {:>6} {:>8} {:>8} {:>8}",
+                "window (UTC)", "count", "min", "max", "avg"
+            );
+            last_series = Some(series);
+        }
+        let window_start: String = row.get(2)?;
+        let count: i64 = row.get(3)?;
+        let (min, max, avg): (f64, f64, f64) = (row.get(4)?, row.get(5)?, row.get(6)?);
+        println!(
+            "  {:<19} {:>6} {:>8.2} {:>8.2} {:>8.2}",
+            window_start, count, min, max, avg
+        );
+    }
+    if last_series.is_none() {
+        println!("no readings recorded yet");
+    }
+    Ok(())
+}
+
+/// Parses durations like `90`, `30s`, `15m`, `1h` or `7d`; plain numbers are seconds.
+fn parse_duration_ms(duration: &str) -> Result<i64, Box<dyn Error>> {
+    let (number, unit_s) = match duration.char_indices().last() {
+        Some((i, 's')) => (&duration[..i], 1),
+        Some((i, 'm')) => (&duration[..i], 60),
+        Some((i, 'h')) => (&duration[..i], 60 * 60),
+        Some((i, 'd')) => (&duration[..i], 24 * 60 * 60),
+        _ => (duration, 1),
+    };
+    let number: i64 = number.parse().map_err(|_| {
+        format!(
+            "invalid duration `{}`, expected e.g. `30s`, `15m` or `1h`",
+            duration
+        )
+    })?;
+    if number <= 0 {
+        return Err(format!("duration `{}` must be positive", duration).into());
+    }
+    number
+        .checked_mul(unit_s * 1000)
+        .ok_or_else(|| format!("duration `{}` is too long", duration).into())
+}
+
+fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration_ms("90").unwrap(), 90_000);
+        assert_eq!(parse_duration_ms("15m").unwrap(), 15 * 60_000);
+        assert_eq!(parse_duration_ms("7d").unwrap(), 7 * 24 * 60 * 60_000);
+        assert!(parse_duration_ms("0s").is_err());
+        assert!(parse_duration_ms("1w").is_err());
+        assert!(parse_duration_ms("").is_err());
+    }
+
+    #[test]
+    fn overlong_durations() {
+        assert!(parse_duration_ms("9223372036854775807d").is_err());
+        assert!(parse_duration_ms("106751991167301d").is_err());
+    }
+}
@@ -0,0 +1,164 @@
+//! Stress test mode: floods the board with valid and malformed messages and
+//! checks whether it is still alive afterwards.
+//!
+//! Run it with `cargo run -- stress`. Rate, payload size and duration are
+//! taken from the `stress_*` configuration keys.
+
+use mqtt_messages::{cmd_topic_fragment, color_topic, hello_topic, temperature_data_topic, RGB8};
+use rand::Rng;
+use rumqttc::{Connection, QoS};
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::session::Session;
+use crate::{CONFIG, UUID};
+
+/// One kind of message the board is bombarded with.
+struct Case {
+    name: &'static str,
+    topic: String,
+    payload: Vec<u8>,
+    sent: u64,
+}
+
+impl Case {
+    fn new(name: &'static str, topic: String, payload: Vec<u8>) -> Self {
+        Self {
+            name,
+            topic,
+            payload,
+            sent: 0,
+        }
+    }
+}
+
+fn cases() -> Vec<Case> {
+    let mut rng = rand::thread_rng();
+    let color = RGB8::new(rng.gen(), rng.gen(), rng.gen());
+    let color = vec![color.r, color.g, color.b];
+    let garbage = (0..CONFIG.stress_payload_size)
+        .map(|_| rng.gen())
+        .collect::<Vec<u8>>();
+    let board_led = format!("{}board_led", cmd_topic_fragment(UUID));
+
+    vec![
+        Case::new("valid color", color_topic(UUID), color.clone()),
+        Case::new("valid command", board_led.clone(), color.clone()),
+        Case::new("oversized color", color_topic(UUID), garbage.clone()),
+        Case::new("oversized command", board_led.clone(), garbage.clone()),
+        Case::new(
+            "oversized unknown command",
+            format!("{}garbage", cmd_topic_fragment(UUID)),
+            garbage,
+        ),
+        Case::new("truncated color", color_topic(UUID), color[..2].to_vec()),
+        Case::new("truncated command", board_led.clone(), color[..1].to_vec()),
+        Case::new("wrong length color", color_topic(UUID), vec![0, 1, 2, 3]),
+        Case::new("empty command", board_led, vec![]),
+    ]
+}
+
+/// What the board has been up to, as far as we can tell.
+#[derive(Default)]
+struct Liveness {
+    last_seen: Option<Instant>,
+    hellos: u64,
+    temperatures: u64,
+}
+
+pub fn run(mut session: Session, mut connection: Connection) -> Result<(), Box<dyn Error>> {
+    session.subscribe(hello_topic(UUID), QoS::AtMostOnce);
+    session.subscribe(temperature_data_topic(UUID), QoS::AtMostOnce);
+
+    let liveness = Arc::new(Mutex::new(Liveness::default()));
+
+    let stress_session = session.clone();
+    let stress_liveness = liveness.clone();
+    let stress = thread::spawn(move || stress(stress_session, stress_liveness));
+
+    session.run(&mut connection, |publish_data| {
+        let mut liveness = liveness.lock().unwrap();
+        if publish_data.topic == hello_topic(UUID) {
+            liveness.hellos += 1;
+        } else if publish_data.topic == temperature_data_topic(UUID) {
+            liveness.temperatures += 1;
+        } else {
+            return;
+        }
+        liveness.last_seen = Some(Instant::now());
+    });
+
+    match stress.join() {
+        Ok(true) => Ok(()),
+        Ok(false) => Err("the board did not survive the stress test".into()),
+        Err(_) => Err("stress test failed".into()),
+    }
+}
+
+/// Publishes all cases in turn, then waits for a sign of life from the board.
+///
+/// Returns whether the board is still alive.
+fn stress(mut session: Session, liveness: Arc<Mutex<Liveness>>) -> bool {
+    let mut cases = cases();
+    let interval = Duration::from_secs(1) / CONFIG.stress_rate_hz.max(1);
+    let duration = Duration::from_secs(CONFIG.stress_duration_s);
+
+    println!(
+        "stressing board {} for {:?}: {} messages/s, {} byte oversized payloads",
+        UUID, duration, CONFIG.stress_rate_hz, CONFIG.stress_payload_size
+    );
+
+    let start = Instant::now();
+    for index in (0..cases.len()).cycle() {
+        if start.elapsed() >= duration {
+            break;
+        }
+        let case = &mut cases[index];
+        session.publish(
+            case.topic.as_str(),
+            QoS::AtMostOnce,
+            false,
+            case.payload.as_slice(),
+        );
+        case.sent += 1;
+        thread::sleep(interval);
+    }
+    let stress_end = Instant::now();
+
+    println!("stress test done after {:?}:", stress_end - start);
+    for case in &cases {
+        println!("  {:>26}: {} sent", case.name, case.sent);
+    }
+    println!("  {}", session.metrics());
+
+    let timeout = Duration::from_secs(CONFIG.stress_liveness_timeout_s);
+    println!("waiting up to {:?} for a sign of life", timeout);
+    let alive = loop {
+        {
+            let liveness = liveness.lock().unwrap();
+            if let Some(last_seen) = liveness.last_seen.filter(|seen| *seen > stress_end) {
+                println!(
+                    "board is alive: seen {:?} after the stress test ({} hellos, {} temperature readings in total)",
+                    last_seen - stress_end,
+                    liveness.hellos,
+                    liveness.temperatures
+                );
+                break true;
+            }
+        }
+        if stress_end.elapsed() > timeout {
+            let liveness = liveness.lock().unwrap();
+            println!(
+                "board did not respond ({} hellos, {} temperature readings in total)",
+                liveness.hellos, liveness.temperatures
+            );
+            break false;
+        }
+        thread::sleep(Duration::from_millis(100));
+    };
+
+    session.stop();
+    alive
+}
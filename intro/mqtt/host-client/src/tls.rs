@@ -0,0 +1,71 @@
+//! TLS transport configuration for brokers that require `mqtts://`.
+
+use rumqttc::{
+    certs, pkcs8_private_keys, rsa_private_keys, ClientConfig, TlsConfiguration, Transport,
+};
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+
+use crate::CONFIG;
+
+/// Builds the TLS transport from the `mqtt_tls_*` configuration keys.
+///
+/// The broker certificate is verified against `mqtt_tls_ca`, using `mqtt_host`
+/// as the server name, which is also what is sent as SNI.
+pub fn transport() -> Result<Transport, Box<dyn Error>> {
+    let mut config = ClientConfig::new();
+
+    if CONFIG.mqtt_tls_ca.is_empty() {
+        return Err("TLS is enabled but `mqtt_tls_ca` is not set".into());
+    }
+    let (valid, _invalid) = config
+        .root_store
+        .add_pem_file(&mut open(CONFIG.mqtt_tls_ca)?)
+        .map_err(|_| format!("could not parse CA bundle {}", CONFIG.mqtt_tls_ca))?;
+    if valid == 0 {
+        return Err(format!("no valid CA certificate in {}", CONFIG.mqtt_tls_ca).into());
+    }
+
+    match (CONFIG.mqtt_tls_client_cert, CONFIG.mqtt_tls_client_key) {
+        ("", "") => {}
+        ("", _) | (_, "") => {
+            return Err("client authentication needs both a certificate and a key".into())
+        }
+        (cert_path, key_path) => {
+            let cert_chain = certs(&mut open(cert_path)?)
+                .map_err(|_| format!("could not parse client certificate {}", cert_path))?;
+            // keys generated by openssl are either PKCS#8 or PKCS#1 (RSA)
+            let mut keys = pkcs8_private_keys(&mut open(key_path)?).unwrap_or_default();
+            if keys.is_empty() {
+                keys = rsa_private_keys(&mut open(key_path)?).unwrap_or_default();
+            }
+            let key = keys
+                .into_iter()
+                .next()
+                .ok_or_else(|| format!("no private key found in {}", key_path))?;
+            config.set_single_client_cert(cert_chain, key)?;
+        }
+    }
+
+    config.enable_sni = CONFIG.mqtt_tls_sni;
+
+    let alpn = CONFIG
+        .mqtt_tls_alpn
+        .split(',')
+        .map(str::trim)
+        .filter(|protocol| !protocol.is_empty())
+        .map(|protocol| protocol.as_bytes().to_vec())
+        .collect::<Vec<_>>();
+    config.set_protocols(&alpn);
+
+    Ok(Transport::tls_with_config(TlsConfiguration::Rustls(
+        Arc::new(config),
+    )))
+}
+
+fn open(path: &str) -> Result<BufReader<File>, Box<dyn Error>> {
+    let file = File::open(path).map_err(|e| format!("could not open {}: {}", path, e))?;
+    Ok(BufReader::new(file))
+}
@@ -1,5 +1,31 @@
+use std::{fs, path::Path};
+
+/// PEM files that are embedded into the firmware if they exist in `certs/`.
+const CERTIFICATES: &[&str] = &["ca.pem", "client.pem", "client.key"];
+
 fn main() -> anyhow::Result<()> {
+    embed_certificates()?;
+
     // Necessary because of this issue: https://github.com/rust-lang/cargo/issues/9641
     embuild::build::CfgArgs::output_propagated("ESP_IDF")?;
     embuild::build::LinkArgs::output_propagated("ESP_IDF")
 }
+
+/// Copies the certificates to `OUT_DIR` so they can be `include_bytes!`-ed.
+///
+/// Missing certificates are replaced by empty files. mbedTLS expects PEM
+/// data to be NUL terminated, so existing ones get a trailing `\0`.
+fn embed_certificates() -> anyhow::Result<()> {
+    let out_dir = std::env::var("OUT_DIR")?;
+    for name in CERTIFICATES {
+        let source = Path::new("certs").join(name);
+        println!("cargo:rerun-if-changed={}", source.display());
+
+        let mut pem = fs::read(&source).unwrap_or_default();
+        if !pem.is_empty() {
+            pem.push(0);
+        }
+        fs::write(Path::new(&out_dir).join(name), pem)?;
+    }
+    Ok(())
+}
@@ -1,25 +1,50 @@
-use std::{convert::TryFrom, thread::sleep, time::Duration};
+use std::{
+    convert::TryFrom,
+    sync::{Arc, Mutex},
+    thread::{self, sleep},
+    time::{Duration, Instant},
+};
 
+use anyhow::{anyhow, bail, Context};
 use bsc::{
+    effects::{Effect, Effects},
+    fault::{self, FaultCode},
     led::{RGB8, WS2812RMT},
-    temp_sensor::BoardTempSensor,
+    provisioning::{self, Settings},
+    sensors::{Celsius, TemperatureSource},
+    status::{self, Status, StatusIndicator},
+    temp_sensor::{BoardTempSensor, FilterConfig, Smoothing},
     wifi::wifi,
 };
 use embedded_svc::mqtt::client::{
-    Client, Details::Complete, Event::Received, Message, Publish, QoS,
+    Client,
+    Details::Complete,
+    Event::{Connected, Disconnected, Received},
+    Message, Publish, QoS,
 };
 use esp32_c3_dkc02_bsc as bsc;
 use esp_idf_svc::{
     log::EspLogger,
     mqtt::client::{EspMqttClient, EspMqttMessage, MqttClientConfiguration},
+    tls::X509,
 };
 // If using the `binstart` feature of `esp-idf-sys`, always keep this module imported
 use esp_idf_sys as _;
-use log::{error, info};
-use mqtt_messages::{hello_topic, ColorData};
+use log::{error, info, warn};
+use mqtt_messages::{
+    cmd_topic_fragment, hello_topic, ColorData, Command, LedScript, RawCommandData,
+};
 
 const UUID: &'static str = get_uuid::uuid();
 
+/// CA certificate of the broker, embedded by `build.rs` from `certs/ca.pem`.
+/// Empty if there is none, in which case the ESP-IDF certificate bundle is used.
+const CA_CERTIFICATE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/ca.pem"));
+/// Client certificate and its key, from `certs/client.pem` and `certs/client.key`.
+/// Empty if the broker doesn't ask for one.
+const CLIENT_CERTIFICATE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/client.pem"));
+const CLIENT_KEY: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/client.key"));
+
 #[toml_cfg::toml_config]
 pub struct Config {
     #[default("localhost")]
@@ -28,6 +53,10 @@ pub struct Config {
     mqtt_user: &'static str,
     #[default("")]
     mqtt_pass: &'static str,
+    #[default(1883)]
+    mqtt_port: u16,
+    #[default(false)]
+    mqtt_tls: bool,
     #[default("")]
     wifi_ssid: &'static str,
     #[default("")]
@@ -39,65 +68,258 @@ fn main() -> anyhow::Result<()> {
 
     EspLogger::initialize_default();
 
+    // Errors and panics blink their fault code on the LED, then restart the board
+    fault::install_panic_hook();
+    if let Some(fault) = fault::take_last_fault() {
+        warn!("previous boot failed with {}", fault);
+    }
+    fault::run(run)
+}
+
+fn run() -> anyhow::Result<()> {
     let app_config = CONFIG;
 
     info!("our UUID is:");
     info!("{}", UUID);
 
-    let mut temp_sensor = BoardTempSensor::new_taking_peripherals();
+    // the LED shows what the board is up to, until the first color is received
+    let effects = Effects::start(WS2812RMT::new()?)?;
+    status::install(StatusIndicator::new(effects.clone()))?;
 
-    let mut led = WS2812RMT::new()?;
-    led.set_pixel(RGB8::new(1, 1, 0))?;
+    // `cfg.toml` is stored in NVS on the first start; later the settings come from NVS,
+    // and can be changed in the provisioning portal
+    let settings = provisioning::settings_or_portal(&Settings {
+        wifi_ssid: app_config.wifi_ssid.into(),
+        wifi_psk: app_config.wifi_psk.into(),
+        mqtt_host: app_config.mqtt_host.into(),
+        mqtt_port: app_config.mqtt_port,
+        mqtt_user: app_config.mqtt_user.into(),
+        mqtt_pass: app_config.mqtt_pass.into(),
+        mqtt_tls: app_config.mqtt_tls,
+    })?;
 
-    let _wifi = wifi(app_config.wifi_ssid, app_config.wifi_psk)?;
+    let mut temp_sensor = BoardTempSensor::take()?;
+    // average 8 conversions per reading, dropping those more than 2℃ off, and smooth the readings
+    temp_sensor.set_filter(
+        FilterConfig::default()
+            .oversampling(8)
+            .max_deviation(Some(2.))
+            .smoothing(Smoothing::Exponential(0.3)),
+    );
 
-    let mqtt_config = MqttClientConfiguration::default();
+    let led = LedPlayer::new(effects);
 
-    let broker_url = if !app_config.mqtt_user.is_empty() {
-        format!(
-            "mqtt://{}:{}@{}",
-            app_config.mqtt_user, app_config.mqtt_pass, app_config.mqtt_host
-        )
-    } else {
-        format!("mqtt://{}", app_config.mqtt_host)
-    };
+    let wifi = wifi(&settings.wifi_ssid, &settings.wifi_psk)?;
+    let wifi_watch = wifi.watch();
+
+    let mqtt_config = mqtt_configuration(&settings).context(FaultCode::Mqtt)?;
+
+    let scheme = if settings.mqtt_tls { "mqtts" } else { "mqtt" };
+    let broker_url = format!("{}://{}:{}", scheme, settings.mqtt_host, settings.mqtt_port);
 
+    let mqtt_wifi_watch = wifi_watch.clone();
     let mut client =
         EspMqttClient::new_with_callback(broker_url, &mqtt_config, move |message_event| {
-            if let Some(Ok(Received(message))) = message_event {
-                process_message(message, &mut led);
+            match message_event {
+                Some(Ok(Received(message))) => process_message(message, &led),
+                Some(Ok(Connected(_))) => status::report(Status::MqttConnected),
+                // the Wi-Fi reports itself while it is down
+                Some(Ok(Disconnected)) if mqtt_wifi_watch.is_connected() => {
+                    status::report(Status::WifiUp)
+                }
+                _ => {}
             }
-        })?;
+        })
+        .context(FaultCode::Mqtt)?;
 
     let payload: &[u8] = &[];
-    client.publish(hello_topic(UUID), QoS::AtLeastOnce, true, payload)?;
+    client
+        .publish(hello_topic(UUID), QoS::AtLeastOnce, true, payload)
+        .context(FaultCode::Mqtt)?;
 
-    client.subscribe(mqtt_messages::color_topic(UUID), QoS::AtLeastOnce)?;
+    client
+        .subscribe(mqtt_messages::color_topic(UUID), QoS::AtLeastOnce)
+        .context(FaultCode::Mqtt)?;
+    client
+        .subscribe(format!("{}#", cmd_topic_fragment(UUID)), QoS::AtLeastOnce)
+        .context(FaultCode::Mqtt)?;
 
     loop {
         sleep(Duration::from_secs(1));
-        let temp = temp_sensor.read_owning_peripherals();
-        client.publish(
+        // the Wi-Fi reconnects by itself, there's just nothing to publish to meanwhile
+        if !wifi_watch.is_connected() {
+            continue;
+        }
+        let temp = publish_temperature(&mut client, &mut temp_sensor)?;
+        if let Some(stats) = temp_sensor.statistics() {
+            info!(
+                "temperature {} (last {}: min {:.1}, max {:.1}, std dev {:.2})",
+                temp, stats.count, stats.min, stats.max, stats.std_dev
+            );
+        }
+    }
+}
+
+/// Works with any temperature sensor, e.g. an SHTC3 instead of the on-chip one.
+fn publish_temperature(
+    client: &mut impl Publish<Error = esp_idf_sys::EspError>,
+    source: &mut impl TemperatureSource,
+) -> anyhow::Result<Celsius> {
+    let temp = source
+        .read_temperature()
+        .map_err(|e| anyhow!("could not read temperature: {:?}", e))
+        .context(FaultCode::Sensor)?;
+    client
+        .publish(
             mqtt_messages::temperature_data_topic(UUID),
             QoS::AtLeastOnce,
             false,
-            &temp.to_be_bytes() as &[u8],
-        )?;
+            &temp.0.to_be_bytes() as &[u8],
+        )
+        .context(FaultCode::Mqtt)?;
+    Ok(temp)
+}
+
+/// Credentials are passed as configuration fields instead of being part of the
+/// broker URL, so they don't end up in logs.
+fn mqtt_configuration(settings: &Settings) -> anyhow::Result<MqttClientConfiguration<'_>> {
+    let mut mqtt_config = MqttClientConfiguration::default();
+
+    if !settings.mqtt_user.is_empty() {
+        mqtt_config.username = Some(&settings.mqtt_user);
+        mqtt_config.password = Some(&settings.mqtt_pass);
+    }
+
+    if settings.mqtt_tls {
+        if CA_CERTIFICATE.is_empty() {
+            mqtt_config.crt_bundle_attach = Some(certificate_bundle()?);
+        } else {
+            info!("verifying the broker with the embedded CA certificate");
+            esp_idf_sys::esp!(unsafe {
+                esp_idf_sys::esp_tls_set_global_ca_store(
+                    CA_CERTIFICATE.as_ptr(),
+                    CA_CERTIFICATE.len() as _,
+                )
+            })?;
+            mqtt_config.use_global_ca_store = true;
+        }
+
+        match (CLIENT_CERTIFICATE.is_empty(), CLIENT_KEY.is_empty()) {
+            (true, true) => {}
+            (false, false) => {
+                info!("authenticating with the embedded client certificate");
+                mqtt_config.client_certificate = Some(X509::pem_until_nul(CLIENT_CERTIFICATE));
+                mqtt_config.private_key = Some(X509::pem_until_nul(CLIENT_KEY));
+            }
+            _ => bail!("a client certificate needs both `certs/client.pem` and `certs/client.key`"),
+        }
     }
+
+    Ok(mqtt_config)
+}
+
+type CrtBundleAttach = unsafe extern "C" fn(*mut std::ffi::c_void) -> esp_idf_sys::esp_err_t;
+
+#[cfg(esp_idf_mbedtls_certificate_bundle)]
+fn certificate_bundle() -> anyhow::Result<CrtBundleAttach> {
+    info!("verifying the broker with the ESP-IDF certificate bundle");
+    Ok(esp_idf_sys::esp_crt_bundle_attach)
+}
+
+#[cfg(not(esp_idf_mbedtls_certificate_bundle))]
+fn certificate_bundle() -> anyhow::Result<CrtBundleAttach> {
+    bail!(
+        "no `certs/ca.pem` to verify the broker with, and the certificate bundle \
+         is not built in, see `sdkconfig.tls.defaults`"
+    )
 }
 
-fn process_message(message: &EspMqttMessage, led: &mut WS2812RMT) {
+fn process_message(message: &EspMqttMessage, led: &LedPlayer) {
     match message.details() {
         Complete(token) => {
-            info!("{}", message.topic(token));
+            let topic = message.topic(token);
+            info!("{}", topic);
             let message_data: &[u8] = &message.data();
-            if let Ok(ColorData::BoardLed(color)) = ColorData::try_from(message_data) {
-                info!("{}", color);
-                if let Err(e) = led.set_pixel(color) {
-                    error!("could not set board LED: {:?}", e)
+            if let Some(command_str) = topic.split(&cmd_topic_fragment(UUID)).nth(1) {
+                status::report(Status::CommandReceived);
+                let raw = RawCommandData {
+                    path: command_str,
+                    data: message.data(),
                 };
+                match Command::try_from(raw) {
+                    Ok(Command::BoardLed(color)) => led.set(color),
+                    Ok(Command::BoardLedScript(script)) => led.play(script),
+                    Err(_) => error!("invalid command {}", command_str),
+                }
+            } else if let Ok(ColorData::BoardLed(color)) = ColorData::try_from(message_data) {
+                info!("{}", color);
+                led.set(color);
             }
         }
         _ => error!("could not set board LED"),
     }
 }
+
+/// Shows colors and plays `LedScript`s on the board LED, through the effects thread.
+///
+/// Every new color or script replaces the script that is currently playing.
+struct LedPlayer {
+    state: Arc<Mutex<LedState>>,
+}
+
+struct LedState {
+    effects: Effects,
+    /// Incremented by every color or script. Behind the same lock as `effects`,
+    /// so a replaced script can't show another frame.
+    generation: u32,
+}
+
+impl LedPlayer {
+    const FRAME: Duration = Duration::from_millis(20);
+
+    fn new(effects: Effects) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(LedState {
+                effects,
+                generation: 0,
+            })),
+        }
+    }
+
+    fn set(&self, color: RGB8) {
+        let mut state = self.state.lock().unwrap();
+        state.generation += 1;
+        state.effects.set(Effect::Solid(color));
+    }
+
+    fn play(&self, script: LedScript) {
+        let generation = {
+            let mut state = self.state.lock().unwrap();
+            state.generation += 1;
+            state.generation
+        };
+        info!("playing LED script: {} keyframes", script.keyframes.len());
+
+        let state = self.state.clone();
+        let player = thread::Builder::new().stack_size(4096).spawn(move || {
+            let start = Instant::now();
+            loop {
+                let elapsed = start.elapsed();
+                {
+                    let state = state.lock().unwrap();
+                    if state.generation != generation {
+                        break;
+                    }
+                    state.effects.set(Effect::Solid(script.color_at(elapsed)));
+                }
+                if script.finished(elapsed) {
+                    break;
+                }
+                sleep(Self::FRAME);
+            }
+        });
+        if let Err(e) = player {
+            error!("could not start LED script: {:?}", e);
+        }
+    }
+}